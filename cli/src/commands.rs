@@ -0,0 +1,9 @@
+pub mod ad_hoc;
+pub mod devices;
+pub mod groups;
+pub mod host_keys;
+pub mod profiles;
+pub mod reports;
+pub mod rules;
+pub mod scans;
+pub mod waivers;
@@ -0,0 +1,53 @@
+use std::io::Read;
+use std::sync::Arc;
+use std::{env, fs, io, path::PathBuf};
+
+use anyhow::{Context, Result};
+
+use scan_core::{
+    db::models::Rule,
+    scanner::{Scanner, host_keys::KnownHosts, sink::MemorySink},
+};
+
+use crate::commands::rules::load_rules;
+use crate::config::Config;
+
+pub async fn run(config: Option<PathBuf>) -> Result<()> {
+    let contents = if let Some(path) = config {
+        fs::read_to_string(path)?
+    } else {
+        let mut buffer = String::new();
+        io::stdin().read_to_string(&mut buffer)?;
+        buffer
+    };
+    let config: Config = toml::from_str(&contents)?;
+    let settings = &config.settings;
+
+    let files: Vec<PathBuf> =
+        settings.check_files.iter().map(PathBuf::from).collect();
+    let rules: Vec<Rule> = load_rules(&files)?
+        .into_values()
+        .map(|(rule, _)| rule)
+        .filter(|rule| !settings.exclusion_ids.contains(&rule.id))
+        .collect();
+
+    let known_hosts = match &settings.known_hosts {
+        Some(path) => path.clone(),
+        None => {
+            let home = env::var("HOME").context("HOME is not set")?;
+            format!("{}/.ssh/known_hosts", home)
+        }
+    };
+
+    let sink = Arc::new(MemorySink::new());
+    let scanner = Scanner::new(
+        sink.clone(),
+        Arc::new(KnownHosts::from_file(&known_hosts)?),
+    )
+    .with_options(settings.scan.options()?);
+    scanner.run(rules, config.targets()?).await?;
+
+    let json = serde_json::to_string_pretty(&sink.reports())?;
+    println!("{}", json);
+    Ok(())
+}
@@ -0,0 +1,207 @@
+use std::{env, fs, path::PathBuf};
+
+use anyhow::{Context, Result, bail};
+use clap::Subcommand;
+
+use scan_core::{
+    db::{
+        Db,
+        models::{Credential, NewDevice, ParamValues},
+    },
+    scanner::params::resolve_params,
+};
+
+use crate::config::parse_param;
+
+#[derive(Subcommand, Debug)]
+pub enum DeviceCommand {
+    /// List devices, optionally filtered by tag and OS family
+    List {
+        /// Only list devices carrying all of these tags
+        #[arg(long = "tag")]
+        tags: Vec<String>,
+        #[arg(long)]
+        os: Option<String>,
+    },
+    /// Add a device to the inventory
+    Add(DeviceArgs),
+    /// Replace a device's details and credentials
+    Update {
+        id: i64,
+        #[command(flatten)]
+        device: DeviceArgs,
+    },
+    /// Remove a device and all of its scans
+    Remove { id: i64 },
+    /// Show a device's rule parameter overrides
+    Params { id: i64 },
+    /// Override a rule's parameters for a device
+    SetParams {
+        id: i64,
+        rule_id: String,
+        /// Parameter value, as NAME=VALUE; may be repeated
+        #[arg(long = "param", value_parser = parse_param, required = true)]
+        params: Vec<(String, serde_json::Value)>,
+    },
+    /// Drop a device's overrides of a rule's parameters
+    ClearParams { id: i64, rule_id: String },
+}
+
+#[derive(clap::Args, Debug)]
+pub struct DeviceArgs {
+    /// Host name or IP address of the device
+    #[arg(long)]
+    pub address: String,
+    #[arg(long, default_value_t = 22)]
+    pub port: u16,
+    /// Friendly name shown in listings and reports
+    #[arg(long)]
+    pub name: Option<String>,
+    #[arg(long)]
+    pub username: String,
+    /// Free-form tag; may be repeated
+    #[arg(long = "tag")]
+    pub tags: Vec<String>,
+    /// Keep the device in the inventory but leave it out of scans
+    #[arg(long)]
+    pub disabled: bool,
+    #[command(flatten)]
+    pub credential: CredentialArgs,
+    /// The private key is protected by a passphrase, read from
+    /// KEY_PASSPHRASE or prompted for
+    #[arg(long, requires = "key_file")]
+    pub passphrase: bool,
+}
+
+#[derive(clap::Args, Debug)]
+#[group(required = true, multiple = false)]
+pub struct CredentialArgs {
+    /// Authenticate with a password, read from DEVICE_PASSWORD or prompted
+    /// for
+    #[arg(long)]
+    pub password: bool,
+    /// Authenticate with the private key stored in this file
+    #[arg(long)]
+    pub key_file: Option<PathBuf>,
+    /// Authenticate through the local SSH agent
+    #[arg(long)]
+    pub agent: bool,
+}
+
+impl DeviceArgs {
+    fn device(self: &Self) -> NewDevice {
+        NewDevice {
+            address: self.address.clone(),
+            port: self.port as i32,
+            name: self.name.clone(),
+            username: self.username.clone(),
+            tags: self.tags.clone(),
+            enabled: !self.disabled,
+        }
+    }
+
+    fn credential(self: &Self) -> Result<Credential> {
+        let credential = &self.credential;
+        if credential.password {
+            return Ok(Credential::Password {
+                password: read_secret("DEVICE_PASSWORD", "Password: ")?,
+            });
+        }
+        if let Some(path) = &credential.key_file {
+            let key = fs::read_to_string(path).context(format!(
+                "Failed to read private key '{}'",
+                path.display()
+            ))?;
+            let passphrase = match self.passphrase {
+                true => Some(read_secret("KEY_PASSPHRASE", "Passphrase: ")?),
+                false => None,
+            };
+            return Ok(Credential::PrivateKey { key, passphrase });
+        }
+        if credential.agent {
+            return Ok(Credential::Agent);
+        }
+        bail!("No credential given")
+    }
+}
+
+/// Reads a secret from the environment variable `var`, or prompts for it
+/// without echoing. Secrets are never taken as arguments, where `ps` and
+/// shell history would expose them.
+fn read_secret(var: &str, prompt: &str) -> Result<String> {
+    match env::var(var) {
+        Ok(secret) => Ok(secret),
+        Err(_) => rpassword::prompt_password(prompt)
+            .context(format!("Failed to read secret; set {} instead", var)),
+    }
+}
+
+pub async fn run(db: &Db, cmd: DeviceCommand) -> Result<()> {
+    match cmd {
+        DeviceCommand::List { tags, os } => {
+            for device in db.find_devices(tags, os).await? {
+                let os = match (&device.os_family, &device.os_version) {
+                    (Some(family), Some(version)) => {
+                        format!("{} {}", family, version)
+                    }
+                    (Some(family), None) => family.clone(),
+                    _ => "-".to_string(),
+                };
+                println!(
+                    "{}\t{}\t{}:{}\t{}\t{:?}\t{}\t[{}]{}",
+                    device.id,
+                    device.name.as_deref().unwrap_or("-"),
+                    device.address,
+                    device.port,
+                    device.username,
+                    device.credential_kind,
+                    os,
+                    device.tags.join(","),
+                    if device.enabled { "" } else { "\tdisabled" }
+                );
+            }
+        }
+        DeviceCommand::Add(args) => {
+            let credential = args.credential()?;
+            let device = db.add_device(args.device(), credential).await?;
+            println!("Added device {} ({})", device.id, device.address);
+        }
+        DeviceCommand::Update { id, device } => {
+            let credential = device.credential()?;
+            let device =
+                db.update_device(id, device.device(), credential).await?;
+            println!("Updated device {} ({})", device.id, device.address);
+        }
+        DeviceCommand::Remove { id } => {
+            if db.remove_device(id).await? == 0 {
+                bail!("No device with id {}", id);
+            }
+            println!("Removed device {}", id);
+        }
+        DeviceCommand::Params { id } => {
+            for entry in db.get_params_for_devices(vec![id]).await? {
+                for (name, value) in entry.params.iter() {
+                    println!("{}\t{} = {}", entry.rule_id, name, value);
+                }
+            }
+        }
+        DeviceCommand::SetParams {
+            id,
+            rule_id,
+            params,
+        } => {
+            let rule = db.get_rule(rule_id).await?;
+            let params: ParamValues = params.into_iter().collect();
+            resolve_params(&rule.params, Some(&params))?;
+            db.set_device_params(id, rule.id.clone(), params).await?;
+            println!("Updated parameters of '{}' for device {}", rule.id, id);
+        }
+        DeviceCommand::ClearParams { id, rule_id } => {
+            if db.remove_device_params(id, rule_id.clone()).await? == 0 {
+                bail!("Device {} has no parameters for '{}'", id, rule_id);
+            }
+            println!("Cleared parameters of '{}' for device {}", rule_id, id);
+        }
+    }
+    Ok(())
+}
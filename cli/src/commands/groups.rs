@@ -0,0 +1,94 @@
+use anyhow::{Context, Result, bail};
+use clap::Subcommand;
+
+use scan_core::db::{Db, models::DeviceGroup};
+
+#[derive(Subcommand, Debug)]
+pub enum GroupCommand {
+    /// List all device groups
+    List,
+    /// List the devices in a group
+    Show { name: String },
+    /// Create a device group
+    Add {
+        name: String,
+        #[arg(long)]
+        description: Option<String>,
+    },
+    /// Remove a device group, leaving its devices in the inventory
+    Remove { name: String },
+    /// Add devices to a group
+    AddDevices {
+        name: String,
+        #[arg(required = true)]
+        device_ids: Vec<i64>,
+    },
+    /// Remove devices from a group
+    RemoveDevices {
+        name: String,
+        #[arg(required = true)]
+        device_ids: Vec<i64>,
+    },
+}
+
+async fn group(db: &Db, name: String) -> Result<DeviceGroup> {
+    db.get_group_by_name(name.clone())
+        .await?
+        .context(format!("No device group named '{}'", name))
+}
+
+pub async fn run(db: &Db, cmd: GroupCommand) -> Result<()> {
+    match cmd {
+        GroupCommand::List => {
+            for group in db.get_all_groups().await? {
+                println!(
+                    "{}\t{}\t{}",
+                    group.id,
+                    group.name,
+                    group.description.unwrap_or_default()
+                );
+            }
+        }
+        GroupCommand::Show { name } => {
+            let group = group(db, name).await?;
+            for device in db.get_group_devices(group.id).await? {
+                println!(
+                    "{}\t{}\t{}:{}",
+                    device.id,
+                    device.name.as_deref().unwrap_or("-"),
+                    device.address,
+                    device.port
+                );
+            }
+        }
+        GroupCommand::Add { name, description } => {
+            let group = db.add_group(name, description).await?;
+            println!("Added group {} ({})", group.id, group.name);
+        }
+        GroupCommand::Remove { name } => {
+            let group = group(db, name).await?;
+            if db.remove_group(group.id).await? == 0 {
+                bail!("No device group named '{}'", group.name);
+            }
+            println!("Removed group {}", group.name);
+        }
+        GroupCommand::AddDevices { name, device_ids } => {
+            let group = group(db, name).await?;
+            db.get_devices(device_ids.clone()).await?;
+            for id in device_ids {
+                db.add_group_member(group.id, id).await?;
+            }
+            println!("Updated group {}", group.name);
+        }
+        GroupCommand::RemoveDevices { name, device_ids } => {
+            let group = group(db, name).await?;
+            for id in device_ids {
+                if db.remove_group_member(group.id, id).await? == 0 {
+                    eprintln!("Device {} is not in group {}", id, group.name);
+                }
+            }
+            println!("Updated group {}", group.name);
+        }
+    }
+    Ok(())
+}
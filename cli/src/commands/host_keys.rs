@@ -0,0 +1,101 @@
+use std::{fs, path::PathBuf};
+
+use anyhow::{Context, Result, bail};
+use clap::Subcommand;
+
+use scan_core::{
+    db::{Db, models::HostKeyStatus},
+    scanner::ssh::parse_known_hosts,
+};
+
+#[derive(Subcommand, Debug)]
+pub enum HostKeyCommand {
+    /// List pinned host keys, optionally for a single device
+    List { device_id: Option<i64> },
+    /// Trust a pending host key
+    Approve { id: i64 },
+    /// Stop trusting a host key
+    Revoke { id: i64 },
+    /// Trust the keys a known_hosts file lists for a device
+    Import {
+        device_id: i64,
+        known_hosts: PathBuf,
+    },
+}
+
+pub async fn run(db: &Db, cmd: HostKeyCommand) -> Result<()> {
+    match cmd {
+        HostKeyCommand::List { device_id } => {
+            let keys = match device_id {
+                Some(id) => db.get_host_keys_for_device(id).await?,
+                None => db.get_all_host_keys().await?,
+            };
+            for key in keys {
+                println!(
+                    "{}\tdevice={}\t{:?}\t{} {}",
+                    key.id,
+                    key.device_id,
+                    key.status,
+                    key.algorithm,
+                    key.fingerprint
+                );
+            }
+        }
+        HostKeyCommand::Approve { id } => {
+            let key = db
+                .update_host_key_status(id, HostKeyStatus::Trusted)
+                .await?;
+            println!(
+                "Approved {} for device {}",
+                key.fingerprint, key.device_id
+            );
+        }
+        HostKeyCommand::Revoke { id } => {
+            let key = db
+                .update_host_key_status(id, HostKeyStatus::Revoked)
+                .await?;
+            println!(
+                "Revoked {} for device {}",
+                key.fingerprint, key.device_id
+            );
+        }
+        HostKeyCommand::Import {
+            device_id,
+            known_hosts,
+        } => {
+            let device = db
+                .get_device(device_id)
+                .await?
+                .context(format!("No device with id {}", device_id))?;
+            let contents = fs::read_to_string(&known_hosts)?;
+            let keys = parse_known_hosts(
+                &contents,
+                device.address.as_str(),
+                device.port as u16,
+            );
+            if keys.is_empty() {
+                bail!(
+                    "No keys for '{}' found in '{}'",
+                    device.address,
+                    known_hosts.display()
+                );
+            }
+            for key in keys {
+                let pinned = db
+                    .add_host_key(device_id, &key, HostKeyStatus::Trusted)
+                    .await?;
+                match pinned.status {
+                    HostKeyStatus::Revoked => eprintln!(
+                        "Skipped {} {}: the key has been revoked",
+                        key.algorithm, key.fingerprint
+                    ),
+                    _ => println!(
+                        "Imported {} {}",
+                        key.algorithm, key.fingerprint
+                    ),
+                }
+            }
+        }
+    }
+    Ok(())
+}
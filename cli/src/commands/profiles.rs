@@ -0,0 +1,180 @@
+use anyhow::{Context, Result, bail};
+use clap::{Subcommand, ValueEnum};
+
+use scan_core::{
+    db::{
+        Db,
+        models::{ParamValues, Profile, SeverityLevel},
+    },
+    scanner::{params::override_defaults, profiles::RuleSource},
+};
+
+use crate::config::parse_param;
+
+#[derive(Subcommand, Debug)]
+pub enum ProfileCommand {
+    /// List all profiles
+    List,
+    /// List the rules a profile runs, inherited ones included
+    Show { name: String },
+    /// Create a profile
+    Add {
+        name: String,
+        #[arg(long)]
+        description: Option<String>,
+        /// Inherit every rule of this profile
+        #[arg(long)]
+        extends: Option<String>,
+    },
+    /// Remove a profile
+    Remove { name: String },
+    /// Add rules to a profile
+    AddRules {
+        name: String,
+        #[arg(required = true)]
+        rule_ids: Vec<String>,
+        /// Severity of these rules within the profile
+        #[arg(long, value_enum)]
+        severity: Option<Severity>,
+        /// Override a parameter default, as NAME=VALUE; may be repeated
+        #[arg(long = "param", value_parser = parse_param)]
+        params: Vec<(String, serde_json::Value)>,
+    },
+    /// Exclude rules the profile would otherwise inherit
+    Exclude {
+        name: String,
+        #[arg(required = true)]
+        rule_ids: Vec<String>,
+    },
+    /// Drop a profile's own entries for rules
+    RemoveRules {
+        name: String,
+        #[arg(required = true)]
+        rule_ids: Vec<String>,
+    },
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum Severity {
+    Info,
+    Low,
+    Medium,
+    High,
+    Critical,
+}
+
+impl From<Severity> for SeverityLevel {
+    fn from(severity: Severity) -> Self {
+        match severity {
+            Severity::Info => SeverityLevel::Info,
+            Severity::Low => SeverityLevel::Low,
+            Severity::Medium => SeverityLevel::Medium,
+            Severity::High => SeverityLevel::High,
+            Severity::Critical => SeverityLevel::Critical,
+        }
+    }
+}
+
+async fn profile(db: &Db, name: String) -> Result<Profile> {
+    db.get_profile_by_name(name.clone())
+        .await?
+        .context(format!("No profile named '{}'", name))
+}
+
+pub async fn run(db: &Db, cmd: ProfileCommand) -> Result<()> {
+    match cmd {
+        ProfileCommand::List => {
+            for profile in db.get_all_profiles().await? {
+                println!(
+                    "{}\t{}\t{}",
+                    profile.id,
+                    profile.name,
+                    profile.description.unwrap_or_default()
+                );
+            }
+        }
+        ProfileCommand::Show { name } => {
+            for rule in db.profile_rules(&name).await? {
+                println!("{}\t{:?}\t{}", rule.id, rule.severity, rule.name);
+                for (param, spec) in rule.params.iter() {
+                    println!("    {} = {}", param, spec.default);
+                }
+            }
+        }
+        ProfileCommand::Add {
+            name,
+            description,
+            extends,
+        } => {
+            let parent_id = match extends {
+                Some(parent) => Some(profile(db, parent).await?.id),
+                None => None,
+            };
+            let profile = db.add_profile(name, description, parent_id).await?;
+            println!("Added profile {} ({})", profile.id, profile.name);
+        }
+        ProfileCommand::Remove { name } => {
+            let profile = profile(db, name).await?;
+            if db.remove_profile(profile.id).await? == 0 {
+                bail!("No profile named '{}'", profile.name);
+            }
+            println!("Removed profile {}", profile.name);
+        }
+        ProfileCommand::AddRules {
+            name,
+            rule_ids,
+            severity,
+            params,
+        } => {
+            let profile = profile(db, name).await?;
+            let params: ParamValues = params.into_iter().collect();
+            // Checked before anything is stored, so a typo fails here rather
+            // than every later scan of the profile.
+            for id in &rule_ids {
+                let mut specs = db
+                    .get_rule(id.clone())
+                    .await
+                    .context(format!("No rule '{}'", id))?
+                    .params
+                    .0;
+                override_defaults(&mut specs, &params)
+                    .context(format!("Invalid parameters for '{}'", id))?;
+            }
+            for id in rule_ids {
+                db.set_profile_rule(
+                    profile.id,
+                    id,
+                    severity.map(SeverityLevel::from),
+                    false,
+                    params.clone(),
+                )
+                .await?;
+            }
+            println!("Updated profile {}", profile.name);
+        }
+        ProfileCommand::Exclude { name, rule_ids } => {
+            let profile = profile(db, name).await?;
+            for id in rule_ids {
+                db.set_profile_rule(
+                    profile.id,
+                    id,
+                    None,
+                    true,
+                    ParamValues::new(),
+                )
+                .await?;
+            }
+            println!("Updated profile {}", profile.name);
+        }
+        ProfileCommand::RemoveRules { name, rule_ids } => {
+            let profile = profile(db, name).await?;
+            for id in rule_ids {
+                if db.remove_profile_rule(profile.id, id.clone()).await? == 0 {
+                    eprintln!("'{}' is not in profile {}", id, profile.name);
+                }
+            }
+            println!("Updated profile {}", profile.name);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,157 @@
+use std::collections::BTreeMap;
+
+use anyhow::{Context, Result};
+use clap::{Subcommand, ValueEnum};
+use serde::Serialize;
+
+use scan_core::db::{
+    Db,
+    models::{CheckStatus, Scan, ScanResult},
+};
+
+#[derive(Subcommand, Debug)]
+pub enum ReportCommand {
+    /// Report on a single scan
+    Scan {
+        id: i64,
+        #[arg(long, value_enum, default_value_t = Format::Text)]
+        format: Format,
+    },
+    /// Report on the most recent scan of every device
+    Latest {
+        #[arg(long, value_enum, default_value_t = Format::Text)]
+        format: Format,
+    },
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum Format {
+    Text,
+    Json,
+}
+
+#[derive(Debug, Serialize)]
+struct Report {
+    address: String,
+    scan: Scan,
+    summary: BTreeMap<String, usize>,
+    /// Percentage of assessed rules that passed or only warned.
+    score: Option<f64>,
+    results: Vec<ScanResult>,
+}
+
+/// Skipped, not applicable, manual and errored results are left out of the
+/// score; `None` when no rule was assessed at all.
+fn score(results: &[ScanResult]) -> Option<f64> {
+    let assessed: Vec<_> =
+        results.iter().filter(|r| r.status.is_assessed()).collect();
+    if assessed.is_empty() {
+        return None;
+    }
+    let passed = assessed
+        .iter()
+        .filter(|r| r.status != CheckStatus::Fail)
+        .count();
+    Some(100.0 * passed as f64 / assessed.len() as f64)
+}
+
+async fn build_report(db: &Db, scan: Scan) -> Result<Report> {
+    let device = db
+        .get_device(scan.device_id)
+        .await?
+        .context(format!("No device with id {}", scan.device_id))?;
+    let results = db.get_scan_results_for_scan(scan.id).await?;
+    let mut summary = BTreeMap::new();
+    for result in &results {
+        *summary.entry(format!("{:?}", result.status)).or_insert(0) += 1;
+    }
+    Ok(Report {
+        address: device.address,
+        scan,
+        summary,
+        score: score(&results),
+        results,
+    })
+}
+
+fn print_reports(reports: &[Report], format: Format) -> Result<()> {
+    match format {
+        Format::Json => {
+            println!("{}", serde_json::to_string_pretty(reports)?);
+        }
+        Format::Text => {
+            for report in reports {
+                let summary: Vec<String> = report
+                    .summary
+                    .iter()
+                    .map(|(status, count)| format!("{}={}", status, count))
+                    .collect();
+                let score = report
+                    .score
+                    .map_or("-".to_string(), |s| format!("{:.0}%", s));
+                println!(
+                    "{} (scan {}, {:?}): {} score={}",
+                    report.address,
+                    report.scan.id,
+                    report.scan.status,
+                    summary.join(" "),
+                    score
+                );
+                if report.scan.connect_attempts > 1 {
+                    println!(
+                        "    Flaky: connecting took {} attempts",
+                        report.scan.connect_attempts
+                    );
+                }
+                if let Some(reason) = &report.scan.failure_reason {
+                    println!("    Failed: {}", reason);
+                }
+                let manual = report
+                    .results
+                    .iter()
+                    .filter(|r| r.status == CheckStatus::Manual)
+                    .count();
+                if manual > 0 {
+                    println!("    {} result(s) need manual review", manual);
+                }
+                for result in &report.results {
+                    println!(
+                        "    {}\t{:?}\t{:?}\t{}ms\t{}",
+                        result.rule_id,
+                        result.severity,
+                        result.status,
+                        result.duration_ms,
+                        result.details.as_deref().unwrap_or_default()
+                    );
+                    if let Some(id) = result.waiver_id {
+                        println!("        waived by waiver {}", id);
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+pub async fn run(db: &Db, cmd: ReportCommand) -> Result<()> {
+    match cmd {
+        ReportCommand::Scan { id, format } => {
+            let scan = db
+                .get_scan(id)
+                .await?
+                .context(format!("No scan with id {}", id))?;
+            let report = build_report(db, scan).await?;
+            print_reports(&[report], format)
+        }
+        ReportCommand::Latest { format } => {
+            let mut reports = Vec::new();
+            for device in db.get_all_devices().await? {
+                let mut scans = db.get_scans_for_device(device.id).await?;
+                if let Some(scan) = scans.pop() {
+                    reports.push(build_report(db, scan).await?);
+                }
+            }
+            print_reports(&reports, format)
+        }
+    }
+}
@@ -0,0 +1,266 @@
+use std::{collections::BTreeMap, fs, path::PathBuf};
+
+use anyhow::{Context, Result, bail};
+use clap::Subcommand;
+
+use scan_core::{
+    db::{
+        Db,
+        models::{Rule, RuleRevision},
+    },
+    scanner::lua::rules_from_script,
+};
+
+#[derive(Subcommand, Debug)]
+pub enum RuleCommand {
+    /// List all rules
+    List,
+    /// Print a rule and its script
+    Show {
+        id: String,
+        /// Print this past revision instead of the current rule
+        #[arg(long)]
+        version: Option<i32>,
+    },
+    /// List the revisions of a rule
+    History { id: String },
+    /// Add or update every rule defined in the given Lua scripts, or in
+    /// the `.lua` files under the given directories
+    Import {
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+        /// Only report what would change
+        #[arg(long)]
+        dry_run: bool,
+        /// Remove rules not defined by any of the scripts
+        #[arg(long)]
+        prune: bool,
+    },
+    /// Remove a rule
+    Remove { id: String },
+}
+
+/// Expands directories into the `.lua` files beneath them, in name order.
+fn script_files(paths: Vec<PathBuf>) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for path in paths {
+        if !path.is_dir() {
+            files.push(path);
+            continue;
+        }
+        let mut entries = fs::read_dir(&path)
+            .context(format!("Failed to read '{}'", path.display()))?
+            .map(|entry| Ok(entry?.path()))
+            .collect::<Result<Vec<PathBuf>>>()?;
+        entries.sort();
+        let entries = entries
+            .into_iter()
+            .filter(|p| p.is_dir() || p.extension().is_some_and(|e| e == "lua"))
+            .collect();
+        files.extend(script_files(entries)?);
+    }
+    Ok(files)
+}
+
+/// Loads the rules defined by each script, by id along with the script that
+/// defines them. Fails if two rules share an id.
+pub fn load_rules(
+    files: &[PathBuf],
+) -> Result<BTreeMap<String, (Rule, String)>> {
+    let mut rules: BTreeMap<String, (Rule, String)> = BTreeMap::new();
+    for path in files {
+        let code = fs::read_to_string(path)
+            .context(format!("Failed to read '{}'", path.display()))?;
+        let name = path.display().to_string();
+        for rule in rules_from_script(&code, &name)? {
+            if let Some((_, other)) = rules.get(&rule.id) {
+                bail!(
+                    "Rule '{}' is defined in both '{}' and '{}'",
+                    rule.id,
+                    other,
+                    name
+                );
+            }
+            rules.insert(rule.id.clone(), (rule, name.clone()));
+        }
+    }
+    Ok(rules)
+}
+
+/// Upserts the rules defined by the scripts under `paths` by id, printing
+/// what was added, changed, left unchanged and removed. A rule counts as
+/// changed when its script or metadata differ from the stored rule.
+async fn import(
+    db: &Db,
+    paths: Vec<PathBuf>,
+    dry_run: bool,
+    prune: bool,
+) -> Result<()> {
+    let imported = load_rules(&script_files(paths)?)?;
+
+    let mut existing: BTreeMap<String, Rule> = db
+        .get_all_rules()
+        .await?
+        .into_iter()
+        .map(|rule| (rule.id.clone(), rule))
+        .collect();
+    let (mut added, mut changed, mut unchanged) = (0, 0, 0);
+    for (id, (rule, source)) in imported {
+        match existing.remove(&id) {
+            None => {
+                println!("+ {}\t{}", id, source);
+                added += 1;
+                if !dry_run {
+                    db.add_rule(rule).await?;
+                }
+            }
+            Some(current) if current.content_hash() == rule.content_hash() => {
+                println!("= {}\t{}", id, source);
+                unchanged += 1;
+            }
+            Some(_) => {
+                println!("~ {}\t{}", id, source);
+                changed += 1;
+                if !dry_run {
+                    db.update_rule(rule).await?;
+                }
+            }
+        }
+    }
+
+    // Whatever is left is stored but no longer defined by any script.
+    // Scan results keep the rules they reference from being removed, so
+    // those are reported the same way whether or not this is a dry run.
+    let (mut removed, mut kept) = (0, Vec::new());
+    for id in existing.into_keys() {
+        if !prune {
+            println!("? {}\tnot in any script; use --prune to remove", id);
+            continue;
+        }
+        let results = db.count_results_for_rule(id.clone()).await?;
+        if results > 0 {
+            println!("! {}\treferenced by {} result(s); not removed", id, results);
+            kept.push(id);
+            continue;
+        }
+        println!("- {}", id);
+        removed += 1;
+        if !dry_run {
+            db.remove_rule(id).await?;
+        }
+    }
+
+    println!(
+        "{} added, {} changed, {} unchanged, {} removed{}",
+        added,
+        changed,
+        unchanged,
+        removed,
+        if dry_run { " (dry run)" } else { "" }
+    );
+    if !dry_run && !kept.is_empty() {
+        bail!(
+            "Could not prune {} rule(s) referenced by scan results: {}",
+            kept.len(),
+            kept.join(", ")
+        );
+    }
+    Ok(())
+}
+
+pub fn print_revision(revision: &RuleRevision) {
+    println!(
+        "{} - {} (version {}, {})",
+        revision.rule_id,
+        revision.name,
+        revision.version,
+        revision.created_at.to_rfc3339()
+    );
+    println!("Severity: {:?}", revision.severity);
+    if let Some(description) = &revision.description {
+        println!("Description: {}", description);
+    }
+    if !revision.os_families.is_empty() {
+        println!(
+            "Applies to: {} ({} - {})",
+            revision.os_families.join(", "),
+            revision.min_os_version.as_deref().unwrap_or("any"),
+            revision.max_os_version.as_deref().unwrap_or("any")
+        );
+    }
+    for (name, spec) in revision.params.iter() {
+        println!("Param {}: {:?} = {}", name, spec.kind, spec.default);
+    }
+    println!("\n{}", revision.script_body);
+}
+
+pub async fn run(db: &Db, cmd: RuleCommand) -> Result<()> {
+    match cmd {
+        RuleCommand::List => {
+            for rule in db.get_all_rules().await? {
+                println!("{}\t{:?}\t{}", rule.id, rule.severity, rule.name);
+            }
+        }
+        RuleCommand::Show {
+            id,
+            version: Some(version),
+        } => {
+            let revision = db
+                .get_rule_revision(id.clone(), version)
+                .await?
+                .context(format!("Rule '{}' has no version {}", id, version))?;
+            print_revision(&revision);
+        }
+        RuleCommand::History { id } => {
+            for revision in db.get_rule_revisions(id).await? {
+                println!(
+                    "v{}\t{}\t{}\t{}",
+                    revision.version,
+                    revision.created_at.to_rfc3339(),
+                    &revision.content_hash[..12],
+                    revision.name
+                );
+            }
+        }
+        RuleCommand::Show { id, version: None } => {
+            let rule = db.get_rule(id).await?;
+            println!("{} - {}", rule.id, rule.name);
+            println!("Severity: {:?}", rule.severity);
+            if !rule.os_families.is_empty() {
+                println!("Applies to: {}", rule.os_families.join(", "));
+            }
+            if rule.min_os_version.is_some() || rule.max_os_version.is_some() {
+                println!(
+                    "OS versions: {} to {}",
+                    rule.min_os_version.as_deref().unwrap_or("any"),
+                    rule.max_os_version.as_deref().unwrap_or("any")
+                );
+            }
+            if let Some(description) = rule.description {
+                println!("Description: {}", description);
+            }
+            for (name, spec) in rule.params.iter() {
+                println!(
+                    "Parameter {} ({:?}, default {}): {}",
+                    name,
+                    spec.kind,
+                    spec.default,
+                    spec.description.as_deref().unwrap_or_default()
+                );
+            }
+            println!("\n{}", rule.script_body);
+        }
+        RuleCommand::Import {
+            paths,
+            dry_run,
+            prune,
+        } => import(db, paths, dry_run, prune).await?,
+        RuleCommand::Remove { id } => {
+            if db.remove_rule(id.clone()).await? == 0 {
+                bail!("No rule with id '{}'", id);
+            }
+            println!("Removed '{}'", id);
+        }
+    }
+    Ok(())
+}
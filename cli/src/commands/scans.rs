@@ -0,0 +1,189 @@
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use clap::Subcommand;
+
+use scan_core::{
+    db::Db,
+    scanner::{Scanner, targets::ScanScope},
+};
+
+use crate::commands::rules::print_revision;
+use crate::config::ScanArgs;
+
+#[derive(Subcommand, Debug)]
+pub enum ScanCommand {
+    /// Scan enabled devices against every rule, or a profile's rules
+    Run {
+        /// Run the rules of this profile
+        #[arg(long)]
+        profile: Option<String>,
+        #[command(flatten)]
+        scope: ScopeArgs,
+        #[command(flatten)]
+        scan: Box<ScanArgs>,
+    },
+    /// List scans, optionally for a single device
+    List {
+        #[arg(long)]
+        device: Option<i64>,
+    },
+    /// Print the results of a scan
+    Show { id: i64 },
+    /// Print the exact rule revision that produced a result
+    Source { result_id: i64 },
+    /// Print the facts gathered from the device during a scan, as JSON
+    Facts { id: i64 },
+}
+
+/// Which devices to scan; every enabled device when nothing is given.
+#[derive(clap::Args, Debug)]
+pub struct ScopeArgs {
+    /// Only scan devices carrying all of these tags
+    #[arg(long = "tag", conflicts_with_all = ["group", "device"])]
+    pub tags: Vec<String>,
+    /// Only scan devices running this OS family
+    #[arg(long, conflicts_with_all = ["group", "device"])]
+    pub os: Option<String>,
+    /// Scan the members of a device group
+    #[arg(long, conflicts_with = "device")]
+    pub group: Option<String>,
+    /// Scan these devices; may be repeated
+    #[arg(long)]
+    pub device: Vec<i64>,
+}
+
+impl ScopeArgs {
+    fn scope(self: Self) -> ScanScope {
+        if let Some(group) = self.group {
+            return ScanScope::Group(group);
+        }
+        match self.device.as_slice() {
+            [] => ScanScope::Matching {
+                tags: self.tags,
+                os_family: self.os,
+            },
+            [id] => ScanScope::Device(*id),
+            _ => ScanScope::Devices(self.device),
+        }
+    }
+}
+
+pub async fn run(db: &Db, cmd: ScanCommand) -> Result<()> {
+    match cmd {
+        ScanCommand::Run {
+            profile,
+            scope,
+            scan,
+        } => {
+            let scanner =
+                Scanner::new(Arc::new(db.clone()), Arc::new(db.clone()))
+                    .with_options(scan.options()?);
+            let scope = scope.scope();
+            match profile {
+                Some(profile) => {
+                    scanner.run_profile(&profile, db, db, &scope).await?
+                }
+                None => {
+                    scanner
+                        .run_scope(db.get_all_rules().await?, db, &scope)
+                        .await?
+                }
+            }
+        }
+        ScanCommand::List { device } => {
+            let scans = match device {
+                Some(id) => db.get_scans_for_device(id).await?,
+                None => db.get_all_scans().await?,
+            };
+            for scan in scans {
+                let started =
+                    scan.started_at.map_or("-".to_string(), |t| t.to_rfc3339());
+                println!(
+                    "{}\tdevice={}\t{:?}\tstarted={}\tattempts={}",
+                    scan.id,
+                    scan.device_id,
+                    scan.status,
+                    started,
+                    scan.connect_attempts
+                );
+            }
+        }
+        ScanCommand::Show { id } => {
+            let scan = db
+                .get_scan(id)
+                .await?
+                .context(format!("No scan with id {}", id))?;
+            println!(
+                "Scan {} of device {}: {:?}",
+                scan.id, scan.device_id, scan.status
+            );
+            if let (Some(start), Some(end)) =
+                (scan.started_at, scan.finished_at)
+            {
+                println!(
+                    "Ran from {} to {} ({}s)",
+                    start.to_rfc3339(),
+                    end.to_rfc3339(),
+                    (end - start).num_seconds()
+                );
+            }
+            if scan.connect_attempts > 1 {
+                println!(
+                    "Connecting took {} attempts",
+                    scan.connect_attempts
+                );
+            }
+            if scan.cache_hits + scan.cache_misses > 0 {
+                println!(
+                    "Command cache: {} hit(s), {} miss(es)",
+                    scan.cache_hits, scan.cache_misses
+                );
+            }
+            if let Some(facts) = &scan.facts {
+                println!(
+                    "Host: {} ({}, kernel {}, {})",
+                    facts.hostname.as_deref().unwrap_or("-"),
+                    facts.os.as_ref().map_or("-", |os| os.family.as_str()),
+                    facts.kernel.as_deref().unwrap_or("-"),
+                    facts.architecture.as_deref().unwrap_or("-")
+                );
+            }
+            if let Some(reason) = scan.failure_reason {
+                println!("Failure reason: {}", reason);
+            }
+            for result in db.get_scan_results_for_scan(id).await? {
+                println!(
+                    "{}\t{}\t{:?}\t{:?}\t{}ms\t{}",
+                    result.id,
+                    result.rule_id,
+                    result.severity,
+                    result.status,
+                    result.duration_ms,
+                    result.details.unwrap_or_default()
+                );
+            }
+        }
+        ScanCommand::Source { result_id } => {
+            let revision = db
+                .get_revision_for_result(result_id)
+                .await?
+                .context(format!(
+                    "No rule revision recorded for result {}",
+                    result_id
+                ))?;
+            print_revision(&revision);
+        }
+        ScanCommand::Facts { id } => {
+            let scan = db
+                .get_scan(id)
+                .await?
+                .context(format!("No scan with id {}", id))?;
+            let facts = scan
+                .facts
+                .context(format!("No facts recorded for scan {}", id))?;
+            println!("{}", serde_json::to_string_pretty(&facts.0)?);
+        }
+    }
+    Ok(())
+}
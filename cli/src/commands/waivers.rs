@@ -0,0 +1,116 @@
+use anyhow::{Context, Result, bail};
+use chrono::{DateTime, NaiveDate, Utc};
+use clap::Subcommand;
+
+use scan_core::db::Db;
+
+#[derive(Subcommand, Debug)]
+pub enum WaiverCommand {
+    /// List waivers
+    List {
+        /// Include expired waivers
+        #[arg(long)]
+        all: bool,
+    },
+    /// Accept the failures of a rule on a device or group until a date
+    Add {
+        rule_id: String,
+        #[arg(long, required_unless_present = "group", conflicts_with = "group")]
+        device: Option<i64>,
+        /// Name of a device group
+        #[arg(long)]
+        group: Option<String>,
+        #[arg(long)]
+        justification: String,
+        #[arg(long)]
+        approver: String,
+        /// Expiry, as YYYY-MM-DD or an RFC 3339 timestamp
+        #[arg(long, value_parser = parse_expiry)]
+        expires: DateTime<Utc>,
+    },
+    /// Remove a waiver
+    Remove { id: i64 },
+}
+
+fn parse_expiry(arg: &str) -> Result<DateTime<Utc>, String> {
+    if let Ok(date) = NaiveDate::parse_from_str(arg, "%Y-%m-%d") {
+        let midnight = date.and_hms_opt(0, 0, 0).unwrap_or_default();
+        return Ok(midnight.and_utc());
+    }
+    DateTime::parse_from_rfc3339(arg)
+        .map(|t| t.with_timezone(&Utc))
+        .map_err(|e| format!("Invalid expiry '{}': {}", arg, e))
+}
+
+pub async fn run(db: &Db, cmd: WaiverCommand) -> Result<()> {
+    match cmd {
+        WaiverCommand::List { all } => {
+            let now = Utc::now();
+            for waiver in db.get_all_waivers().await? {
+                let expired = waiver.expires_at <= now;
+                if expired && !all {
+                    continue;
+                }
+                let scope = match (waiver.device_id, waiver.group_id) {
+                    (Some(id), _) => format!("device={}", id),
+                    (_, Some(id)) => format!("group={}", id),
+                    _ => "-".to_string(),
+                };
+                println!(
+                    "{}\t{}\t{}\tuntil {}{}\t{}: {}",
+                    waiver.id,
+                    waiver.rule_id,
+                    scope,
+                    waiver.expires_at.to_rfc3339(),
+                    if expired { " (expired)" } else { "" },
+                    waiver.approver,
+                    waiver.justification
+                );
+            }
+        }
+        WaiverCommand::Add {
+            rule_id,
+            device,
+            group,
+            justification,
+            approver,
+            expires,
+        } => {
+            if expires <= Utc::now() {
+                bail!("Waiver would already have expired");
+            }
+            let group_id = match group {
+                Some(name) => Some(
+                    db.get_group_by_name(name.clone())
+                        .await?
+                        .context(format!("No device group named '{}'", name))?
+                        .id,
+                ),
+                None => None,
+            };
+            let waiver = db
+                .add_waiver(
+                    rule_id,
+                    device,
+                    group_id,
+                    justification,
+                    approver,
+                    expires,
+                )
+                .await?;
+            println!(
+                "Added waiver {} for '{}' until {}",
+                waiver.id,
+                waiver.rule_id,
+                waiver.expires_at.to_rfc3339()
+            );
+        }
+        WaiverCommand::Remove { id } => {
+            if db.remove_waiver(id).await? == 0 {
+                bail!("No waiver with id {}", id);
+            }
+            println!("Removed waiver {}", id);
+        }
+    }
+    Ok(())
+}
@@ -1,23 +1,298 @@
-use clap::Parser;
+use std::{collections::HashMap, fs, path::PathBuf, time::Duration};
+
+use anyhow::{Context, Result};
+use clap::{Parser, Subcommand};
 use serde::Deserialize;
 
-use scan_core::db::models::Device;
+use scan_core::{
+    db::models::{Credential, ParamValues, Target},
+    scanner::{ScanOptions, retry::FailureKind},
+};
 
-#[derive(Debug, Deserialize)]
+use crate::commands::{
+    devices::DeviceCommand, groups::GroupCommand, host_keys::HostKeyCommand,
+    profiles::ProfileCommand, reports::ReportCommand, rules::RuleCommand,
+    scans::ScanCommand, waivers::WaiverCommand,
+};
+
+/// An ad hoc scan, described entirely by a TOML file.
+#[derive(Deserialize)]
 pub struct Config {
-    pub devices: Vec<Device>,
+    pub devices: Vec<DeviceConfig>,
     pub settings: Settings,
 }
 
+#[derive(Deserialize)]
+pub struct DeviceConfig {
+    pub address: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    pub username: String,
+    pub credential: CredentialConfig,
+    /// Used instead of the detected OS when given.
+    pub os_family: Option<String>,
+    pub os_version: Option<String>,
+    /// Rule parameter overrides, by rule id.
+    #[serde(default)]
+    pub params: HashMap<String, ParamValues>,
+    /// Group names, for `group_limits`.
+    #[serde(default)]
+    pub groups: Vec<String>,
+}
+
+fn default_port() -> u16 {
+    22
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum CredentialConfig {
+    Password {
+        password: String,
+    },
+    PrivateKey {
+        key_file: PathBuf,
+        passphrase: Option<String>,
+    },
+    Agent,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct Settings {
+    #[serde(default)]
     pub exclusion_ids: Vec<String>,
     pub check_files: Vec<String>,
+    /// Defaults to `~/.ssh/known_hosts`.
+    pub known_hosts: Option<String>,
+    #[serde(flatten)]
+    pub scan: ScanArgs,
+}
+
+/// Scan limits, given either as flags or in the `[settings]` of a config.
+#[derive(clap::Args, Debug, Default, Deserialize)]
+pub struct ScanArgs {
+    /// Seconds allowed to open an SSH connection
+    #[arg(long)]
+    pub connect_timeout: Option<u64>,
+    /// Seconds allowed for a single command
+    #[arg(long)]
+    pub command_timeout: Option<u64>,
+    /// Seconds allowed for a whole rule
+    #[arg(long)]
+    pub rule_timeout: Option<u64>,
+    /// Megabytes of memory each rule's Lua state may use
+    #[arg(long)]
+    pub lua_memory_mb: Option<usize>,
+    /// Lua instructions a rule may execute before it is aborted
+    #[arg(long)]
+    pub lua_instructions: Option<u64>,
+    /// Devices scanned at the same time
+    #[arg(long)]
+    pub max_devices: Option<usize>,
+    /// Devices scanned at the same time within one subnet
+    #[arg(long)]
+    pub per_subnet: Option<usize>,
+    /// Prefix length grouping IPv4 addresses into subnets
+    #[arg(long)]
+    pub subnet_prefix: Option<u8>,
+    /// Devices of a group scanned at the same time, as NAME=N; may be
+    /// repeated
+    #[arg(long = "group-limit")]
+    #[serde(default)]
+    pub group_limits: Vec<String>,
+    /// Rules run at the same time on one device
+    #[arg(long)]
+    pub rules_per_device: Option<usize>,
+    /// Commands running at the same time on one device
+    #[arg(long)]
+    pub commands_per_device: Option<usize>,
+    /// New SSH connections opened per second
+    #[arg(long)]
+    pub connects_per_second: Option<u32>,
+    /// Attempts made to connect or run a command, the first one included
+    #[arg(long)]
+    pub retry_attempts: Option<u32>,
+    /// Milliseconds to wait before the first retry; doubled for each retry
+    #[arg(long)]
+    pub retry_backoff_ms: Option<u64>,
+    /// Longest wait between two retries, in milliseconds
+    #[arg(long)]
+    pub retry_max_backoff_ms: Option<u64>,
+    /// Failures worth retrying: connect, connect_timeout, auth, channel or
+    /// command_timeout; may be repeated
+    #[arg(long)]
+    #[serde(default)]
+    pub retry_on: Vec<String>,
+    /// Run every command, even one already run on the device in this scan
+    #[arg(long)]
+    #[serde(default)]
+    pub no_command_cache: bool,
+}
+
+impl ScanArgs {
+    pub fn options(self: &Self) -> Result<ScanOptions> {
+        let mut options = ScanOptions::default();
+        if let Some(secs) = self.connect_timeout {
+            options.connect_timeout = Duration::from_secs(secs);
+        }
+        if let Some(secs) = self.command_timeout {
+            options.command_timeout = Duration::from_secs(secs);
+        }
+        if let Some(secs) = self.rule_timeout {
+            options.rule_timeout = Duration::from_secs(secs);
+        }
+        if let Some(mb) = self.lua_memory_mb {
+            options.lua_limits.memory = mb * 1024 * 1024;
+        }
+        if let Some(instructions) = self.lua_instructions {
+            options.lua_limits.instructions = instructions;
+        }
+
+        let concurrency = &mut options.concurrency;
+        if let Some(n) = self.max_devices {
+            concurrency.max_devices = n;
+        }
+        concurrency.per_subnet = self.per_subnet;
+        if let Some(prefix) = self.subnet_prefix {
+            concurrency.subnet_prefix = prefix;
+        }
+        for limit in &self.group_limits {
+            let (group, n) = limit
+                .split_once('=')
+                .and_then(|(group, n)| Some((group, n.parse().ok()?)))
+                .context(format!("Expected NAME=N, got '{}'", limit))?;
+            concurrency.per_group.insert(group.to_string(), n);
+        }
+        if let Some(n) = self.rules_per_device {
+            concurrency.rules_per_device = n;
+        }
+        if let Some(n) = self.commands_per_device {
+            concurrency.commands_per_device = n;
+        }
+        concurrency.connects_per_second = self.connects_per_second;
+
+        options.cache_commands = !self.no_command_cache;
+
+        let retry = &mut options.retry;
+        if let Some(n) = self.retry_attempts {
+            retry.max_attempts = n.max(1);
+        }
+        if let Some(ms) = self.retry_backoff_ms {
+            retry.initial_backoff = Duration::from_millis(ms);
+        }
+        if let Some(ms) = self.retry_max_backoff_ms {
+            retry.max_backoff = Duration::from_millis(ms);
+        }
+        if !self.retry_on.is_empty() {
+            retry.retry_on = self
+                .retry_on
+                .iter()
+                .map(|kind| {
+                    serde_json::from_value::<FailureKind>(kind.as_str().into())
+                        .context(format!("Unknown failure kind '{}'", kind))
+                })
+                .collect::<Result<_>>()?;
+        }
+        Ok(options)
+    }
+}
+
+impl Config {
+    /// Turns the configured devices into scan targets, numbered in the
+    /// order they appear in the file.
+    pub fn targets(self: &Self) -> Result<Vec<Target>> {
+        self.devices
+            .iter()
+            .enumerate()
+            .map(|(i, device)| {
+                Ok(Target {
+                    device_id: i as i64,
+                    address: device.address.clone(),
+                    port: device.port,
+                    username: device.username.clone(),
+                    credential: device.credential.load()?,
+                    os_family: device.os_family.clone(),
+                    os_version: device.os_version.clone(),
+                    os_override: device.os_family.is_some(),
+                    params: device.params.clone(),
+                    waivers: HashMap::new(),
+                    groups: device.groups.clone(),
+                })
+            })
+            .collect()
+    }
+}
+
+/// Parses a `NAME=VALUE` rule parameter. Values are read as JSON, falling
+/// back to a plain string, so `--param max=3` and `--param fw=ufw` both work.
+pub fn parse_param(arg: &str) -> Result<(String, serde_json::Value), String> {
+    let (name, value) = arg
+        .split_once('=')
+        .ok_or_else(|| format!("Expected NAME=VALUE, got '{}'", arg))?;
+    let value = serde_json::from_str(value)
+        .unwrap_or_else(|_| serde_json::Value::String(value.to_string()));
+    Ok((name.to_string(), value))
+}
+
+impl CredentialConfig {
+    fn load(self: &Self) -> Result<Credential> {
+        let credential = match self {
+            CredentialConfig::Password { password } => Credential::Password {
+                password: password.clone(),
+            },
+            CredentialConfig::PrivateKey {
+                key_file,
+                passphrase,
+            } => Credential::PrivateKey {
+                key: fs::read_to_string(key_file).context(format!(
+                    "Failed to read private key '{}'",
+                    key_file.display()
+                ))?,
+                passphrase: passphrase.clone(),
+            },
+            CredentialConfig::Agent => Credential::Agent,
+        };
+        Ok(credential)
+    }
 }
 
 #[derive(Parser, Debug)]
 #[command(name = "scan_core", version, about = "CLI compliance scanning tool")]
 pub struct Args {
-    #[arg(short, long)]
-    pub config: Option<String>,
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Scan the devices in a TOML config without using a database
+    AdHoc {
+        /// Path to the config file; read from stdin when omitted
+        #[arg(short, long)]
+        config: Option<PathBuf>,
+    },
+    /// Manage the device inventory
+    #[command(subcommand)]
+    Devices(DeviceCommand),
+    /// Manage device groups
+    #[command(subcommand)]
+    Groups(GroupCommand),
+    /// Manage pinned SSH host keys
+    #[command(subcommand)]
+    HostKeys(HostKeyCommand),
+    /// Manage compliance profiles
+    #[command(subcommand)]
+    Profiles(ProfileCommand),
+    /// Manage compliance rules
+    #[command(subcommand)]
+    Rules(RuleCommand),
+    /// Run and inspect scans
+    #[command(subcommand)]
+    Scans(ScanCommand),
+    /// Report on scan results
+    #[command(subcommand)]
+    Reports(ReportCommand),
+    /// Manage waivers for accepted risks
+    #[command(subcommand)]
+    Waivers(WaiverCommand),
 }
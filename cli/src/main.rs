@@ -1,30 +1,38 @@
-use std::io::Read;
-use std::{fs, io};
+use std::env;
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use clap::Parser;
-use cli::config::{Args, Config};
-use scan_core::scanner::Scanner;
+use dotenvy::dotenv;
+
+use cli::commands::{
+    ad_hoc, devices, groups, host_keys, profiles, reports, rules, scans,
+    waivers,
+};
+use cli::config::{Args, Command};
+use scan_core::db::Db;
+
+async fn connect() -> Result<Db> {
+    dotenv().ok();
+    let db_url = env::var("DATABASE_URL")
+        .context("Environment variable 'DATABASE_URL' not set")?;
+    let master_key = env::var("MASTER_KEY")
+        .context("Environment variable 'MASTER_KEY' not set")?;
+    Db::new(db_url.as_str(), master_key.as_str()).await
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    // let args = Args::parse();
-    // let contents = if let Some(path) = args.config {
-    //     fs::read_to_string(path)?
-    // } else {
-    //     let mut buffer = String::new();
-    //     io::stdin().read_to_string(&mut buffer)?;
-    //     buffer
-    // };
-    //
-    // let config: Config = toml::from_str(&contents)?;
-    // let mut scanner = Scanner::new()?;
-    // for file_path in &config.settings.check_files {
-    //     scanner.load_file(file_path)?;
-    // }
-    // scanner.exclude_checks(&config.settings.exclusion_ids)?;
-    // let db = scanner.scan_devices(config.devices).await?;
-    // let json = serde_json::to_string_pretty(&db)?;
-    // println!("{}", json);
-    Ok(())
+    let args = Args::parse();
+
+    match args.command {
+        Command::AdHoc { config } => ad_hoc::run(config).await,
+        Command::Devices(cmd) => devices::run(&connect().await?, cmd).await,
+        Command::Groups(cmd) => groups::run(&connect().await?, cmd).await,
+        Command::HostKeys(cmd) => host_keys::run(&connect().await?, cmd).await,
+        Command::Profiles(cmd) => profiles::run(&connect().await?, cmd).await,
+        Command::Rules(cmd) => rules::run(&connect().await?, cmd).await,
+        Command::Scans(cmd) => scans::run(&connect().await?, cmd).await,
+        Command::Reports(cmd) => reports::run(&connect().await?, cmd).await,
+        Command::Waivers(cmd) => waivers::run(&connect().await?, cmd).await,
+    }
 }
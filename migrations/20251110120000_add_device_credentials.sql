@@ -0,0 +1,14 @@
+CREATE TYPE credential_kind AS ENUM (
+    'password',
+    'private_key',
+    'agent'
+);
+
+ALTER TABLE devices
+    ADD COLUMN credential_kind credential_kind NOT NULL DEFAULT 'password',
+    ALTER COLUMN encrypted_password DROP NOT NULL,
+    ALTER COLUMN password_nonce DROP NOT NULL,
+    ADD COLUMN encrypted_private_key BYTEA,
+    ADD COLUMN private_key_nonce BYTEA,
+    ADD COLUMN encrypted_passphrase BYTEA,
+    ADD COLUMN passphrase_nonce BYTEA;
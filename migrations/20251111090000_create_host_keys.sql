@@ -0,0 +1,15 @@
+CREATE TYPE host_key_status AS ENUM (
+    'pending',
+    'trusted',
+    'revoked'
+);
+
+CREATE TABLE host_keys (
+  id BIGSERIAL PRIMARY KEY,
+  device_id BIGINT NOT NULL REFERENCES devices(id) ON DELETE CASCADE,
+  algorithm TEXT NOT NULL,
+  public_key TEXT NOT NULL,
+  fingerprint TEXT NOT NULL,
+  status host_key_status NOT NULL DEFAULT 'pending',
+  UNIQUE (device_id, public_key)
+);
@@ -0,0 +1,7 @@
+ALTER TABLE scans
+    ADD COLUMN started_at TIMESTAMPTZ,
+    ADD COLUMN finished_at TIMESTAMPTZ;
+
+ALTER TABLE scan_results
+    ADD COLUMN executed_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    ADD COLUMN duration_ms BIGINT NOT NULL DEFAULT 0;
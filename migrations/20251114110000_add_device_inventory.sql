@@ -0,0 +1,18 @@
+ALTER TABLE devices
+    ADD COLUMN port INTEGER NOT NULL DEFAULT 22,
+    ADD COLUMN name TEXT,
+    ADD COLUMN os_family TEXT,
+    ADD COLUMN os_version TEXT,
+    ADD COLUMN tags TEXT[] NOT NULL DEFAULT '{}',
+    ADD COLUMN enabled BOOLEAN NOT NULL DEFAULT TRUE,
+    DROP CONSTRAINT devices_address_key,
+    ADD CONSTRAINT devices_address_port_key UNIQUE (address, port);
+
+-- Addresses used to carry the port, as in '10.0.0.5:2222'.
+UPDATE devices
+SET
+    port = split_part(address, ':', 2)::INTEGER,
+    address = split_part(address, ':', 1)
+WHERE address ~ '^[^:]+:[0-9]+$';
+
+CREATE INDEX devices_tags_idx ON devices USING GIN (tags);
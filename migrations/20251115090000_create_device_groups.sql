@@ -0,0 +1,11 @@
+CREATE TABLE device_groups (
+  id BIGSERIAL PRIMARY KEY,
+  name TEXT NOT NULL UNIQUE,
+  description TEXT
+);
+
+CREATE TABLE device_group_members (
+  group_id BIGINT NOT NULL REFERENCES device_groups(id) ON DELETE CASCADE,
+  device_id BIGINT NOT NULL REFERENCES devices(id) ON DELETE CASCADE,
+  PRIMARY KEY (group_id, device_id)
+);
@@ -0,0 +1,7 @@
+ALTER TYPE check_status ADD VALUE 'not_applicable';
+
+-- An empty os_families list means the rule applies to every OS.
+ALTER TABLE rules
+    ADD COLUMN os_families TEXT[] NOT NULL DEFAULT '{}',
+    ADD COLUMN min_os_version TEXT,
+    ADD COLUMN max_os_version TEXT;
@@ -0,0 +1,3 @@
+ALTER TYPE check_status ADD VALUE 'warn';
+ALTER TYPE check_status ADD VALUE 'skip';
+ALTER TYPE check_status ADD VALUE 'manual';
@@ -0,0 +1,25 @@
+CREATE TABLE profiles (
+  id BIGSERIAL PRIMARY KEY,
+  name TEXT NOT NULL UNIQUE,
+  description TEXT,
+  -- A profile includes every rule of its parent, e.g. Level 2 extends Level 1.
+  parent_id BIGINT REFERENCES profiles(id) ON DELETE RESTRICT
+);
+
+CREATE TABLE profile_rules (
+  profile_id BIGINT NOT NULL REFERENCES profiles(id) ON DELETE CASCADE,
+  rule_id TEXT NOT NULL REFERENCES rules(id) ON DELETE CASCADE,
+  severity severity_level,
+  -- Drops a rule the profile would otherwise inherit from its parent.
+  excluded BOOLEAN NOT NULL DEFAULT FALSE,
+  PRIMARY KEY (profile_id, rule_id)
+);
+
+-- Results keep the severity in effect when they were produced, which a
+-- profile may have overridden.
+ALTER TABLE scan_results ADD COLUMN severity severity_level;
+
+UPDATE scan_results r SET severity = rules.severity
+FROM rules WHERE rules.id = r.rule_id;
+
+ALTER TABLE scan_results ALTER COLUMN severity SET NOT NULL;
@@ -0,0 +1,15 @@
+-- Parameter declarations, keyed by name: { "type": ..., "default": ... }.
+ALTER TABLE rules ADD COLUMN params JSONB NOT NULL DEFAULT '{}';
+
+-- Overrides of parameter values, keyed by name.
+ALTER TABLE profile_rules ADD COLUMN params JSONB NOT NULL DEFAULT '{}';
+
+CREATE TABLE device_rule_params (
+  device_id BIGINT NOT NULL REFERENCES devices(id) ON DELETE CASCADE,
+  rule_id TEXT NOT NULL REFERENCES rules(id) ON DELETE CASCADE,
+  params JSONB NOT NULL DEFAULT '{}',
+  PRIMARY KEY (device_id, rule_id)
+);
+
+-- The parameter values a result was produced with.
+ALTER TABLE scan_results ADD COLUMN params JSONB NOT NULL DEFAULT '{}';
@@ -0,0 +1,18 @@
+ALTER TYPE check_status ADD VALUE 'waived';
+
+CREATE TABLE waivers (
+  id BIGSERIAL PRIMARY KEY,
+  rule_id TEXT NOT NULL REFERENCES rules(id) ON DELETE CASCADE,
+  device_id BIGINT REFERENCES devices(id) ON DELETE CASCADE,
+  group_id BIGINT REFERENCES device_groups(id) ON DELETE CASCADE,
+  justification TEXT NOT NULL,
+  approver TEXT NOT NULL,
+  expires_at TIMESTAMPTZ NOT NULL,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+  CHECK ((device_id IS NULL) <> (group_id IS NULL))
+);
+
+CREATE INDEX waivers_rule_id_idx ON waivers (rule_id);
+
+ALTER TABLE scan_results
+    ADD COLUMN waiver_id BIGINT REFERENCES waivers(id) ON DELETE SET NULL;
@@ -0,0 +1,39 @@
+-- Immutable snapshots of rules. A rule is defined entirely by its script,
+-- so a new revision is recorded whenever the script's SHA-256 changes.
+CREATE TABLE rule_revisions (
+  id BIGSERIAL PRIMARY KEY,
+  rule_id TEXT NOT NULL REFERENCES rules(id) ON DELETE CASCADE,
+  version INTEGER NOT NULL,
+  content_hash TEXT NOT NULL,
+  name TEXT NOT NULL,
+  description TEXT,
+  severity severity_level NOT NULL,
+  script_body TEXT NOT NULL,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+  UNIQUE (rule_id, version)
+);
+
+INSERT INTO rule_revisions
+    (rule_id, version, content_hash, name, description, severity, script_body)
+SELECT
+    id,
+    1,
+    encode(sha256(convert_to(script_body, 'UTF8')), 'hex'),
+    name,
+    description,
+    severity,
+    script_body
+FROM rules;
+
+ALTER TABLE rules
+    ADD COLUMN revision_id BIGINT REFERENCES rule_revisions(id);
+
+UPDATE rules SET revision_id = r.id
+FROM rule_revisions r WHERE r.rule_id = rules.id;
+
+-- Results from before revisions existed are attributed to the first one.
+ALTER TABLE scan_results
+    ADD COLUMN revision_id BIGINT REFERENCES rule_revisions(id);
+
+UPDATE scan_results SET revision_id = r.id
+FROM rule_revisions r WHERE r.rule_id = scan_results.rule_id;
@@ -0,0 +1,2 @@
+-- Attempts needed to connect to the device, so flaky hosts stand out.
+ALTER TABLE scans ADD COLUMN connect_attempts INTEGER NOT NULL DEFAULT 0;
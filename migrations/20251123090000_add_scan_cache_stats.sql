@@ -0,0 +1,3 @@
+-- How often commands were served from the per-scan command cache.
+ALTER TABLE scans ADD COLUMN cache_hits INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE scans ADD COLUMN cache_misses INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,2 @@
+-- Facts gathered from the device at the start of the scan.
+ALTER TABLE scans ADD COLUMN facts JSONB;
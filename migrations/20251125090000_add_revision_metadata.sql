@@ -0,0 +1,16 @@
+-- Revisions also capture the applicability and parameters a rule ran with,
+-- and their hash now covers every field a revision records. Existing
+-- revisions keep their script-only hash, so a rule gets a new revision the
+-- next time it is updated, even if only its hash would differ.
+ALTER TABLE rule_revisions
+    ADD COLUMN os_families TEXT[] NOT NULL DEFAULT '{}',
+    ADD COLUMN min_os_version TEXT,
+    ADD COLUMN max_os_version TEXT,
+    ADD COLUMN params JSONB NOT NULL DEFAULT '{}';
+
+UPDATE rule_revisions r SET
+    os_families = rules.os_families,
+    min_os_version = rules.min_os_version,
+    max_os_version = rules.max_os_version,
+    params = rules.params
+FROM rules WHERE rules.revision_id = r.id;
@@ -1,12 +1,15 @@
 pub mod crypto;
 pub mod models;
 
+use std::collections::HashMap;
+
 use crate::db::crypto::*;
 use crate::db::models::*;
 
 use aes_gcm::Aes256Gcm;
-use anyhow::Result;
-use sqlx::PgPool;
+use anyhow::{Result, bail};
+use chrono::{DateTime, Utc};
+use sqlx::{PgPool, Postgres, Transaction, types::Json};
 
 #[derive(Clone)]
 pub struct Db {
@@ -27,8 +30,25 @@ impl Db {
         let devices = sqlx::query_as!(
             Device,
             r#"
-            SELECT id, address, username, encrypted_password, password_nonce
+            SELECT
+                id,
+                address,
+                port,
+                name,
+                username,
+                os_family,
+                os_version,
+                tags,
+                enabled,
+                credential_kind as "credential_kind: CredentialKind",
+                encrypted_password,
+                password_nonce,
+                encrypted_private_key,
+                private_key_nonce,
+                encrypted_passphrase,
+                passphrase_nonce
             FROM devices
+            ORDER BY id
             "#
         )
         .fetch_all(&self.pool)
@@ -36,79 +56,867 @@ impl Db {
         Ok(devices)
     }
 
-    pub async fn add_device(
+    pub async fn add_device(
+        self: &Self,
+        device: NewDevice,
+        credential: Credential,
+    ) -> Result<Device> {
+        let enc = encrypt_credential(&self.cipher, &credential)?;
+        let result = sqlx::query_as!(
+            Device,
+            r#"
+            INSERT INTO devices (
+                address,
+                port,
+                name,
+                username,
+                tags,
+                enabled,
+                credential_kind,
+                encrypted_password,
+                password_nonce,
+                encrypted_private_key,
+                private_key_nonce,
+                encrypted_passphrase,
+                passphrase_nonce
+            )
+            VALUES (
+                $1, $2, $3, $4, $5, $6, $7::credential_kind,
+                $8, $9, $10, $11, $12, $13
+            )
+            RETURNING
+                id,
+                address,
+                port,
+                name,
+                username,
+                os_family,
+                os_version,
+                tags,
+                enabled,
+                credential_kind as "credential_kind: CredentialKind",
+                encrypted_password,
+                password_nonce,
+                encrypted_private_key,
+                private_key_nonce,
+                encrypted_passphrase,
+                passphrase_nonce
+            "#,
+            device.address,
+            device.port,
+            device.name,
+            device.username,
+            &device.tags,
+            device.enabled,
+            credential.kind() as _,
+            enc.encrypted_password,
+            enc.password_nonce,
+            enc.encrypted_private_key,
+            enc.private_key_nonce,
+            enc.encrypted_passphrase,
+            enc.passphrase_nonce
+        )
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(result)
+    }
+
+    pub async fn get_device(self: &Self, id: i64) -> Result<Option<Device>> {
+        let device = sqlx::query_as!(
+            Device,
+            r#"
+            SELECT
+                id,
+                address,
+                port,
+                name,
+                username,
+                os_family,
+                os_version,
+                tags,
+                enabled,
+                credential_kind as "credential_kind: CredentialKind",
+                encrypted_password,
+                password_nonce,
+                encrypted_private_key,
+                private_key_nonce,
+                encrypted_passphrase,
+                passphrase_nonce
+            FROM devices WHERE id = $1
+            "#,
+            id
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(device)
+    }
+
+    /// Returns the devices with the given ids, failing if any is missing.
+    pub async fn get_devices(
+        self: &Self,
+        ids: Vec<i64>,
+    ) -> Result<Vec<Device>> {
+        let devices = sqlx::query_as!(
+            Device,
+            r#"
+            SELECT
+                id,
+                address,
+                port,
+                name,
+                username,
+                os_family,
+                os_version,
+                tags,
+                enabled,
+                credential_kind as "credential_kind: CredentialKind",
+                encrypted_password,
+                password_nonce,
+                encrypted_private_key,
+                private_key_nonce,
+                encrypted_passphrase,
+                passphrase_nonce
+            FROM devices
+            WHERE id = ANY($1)
+            ORDER BY id
+            "#,
+            &ids
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        if let Some(missing) =
+            ids.iter().find(|id| !devices.iter().any(|d| d.id == **id))
+        {
+            bail!("No device with id {}", missing);
+        }
+        Ok(devices)
+    }
+
+    pub async fn get_devices_by_tag(
+        self: &Self,
+        tag: String,
+    ) -> Result<Vec<Device>> {
+        let devices = sqlx::query_as!(
+            Device,
+            r#"
+            SELECT
+                id,
+                address,
+                port,
+                name,
+                username,
+                os_family,
+                os_version,
+                tags,
+                enabled,
+                credential_kind as "credential_kind: CredentialKind",
+                encrypted_password,
+                password_nonce,
+                encrypted_private_key,
+                private_key_nonce,
+                encrypted_passphrase,
+                passphrase_nonce
+            FROM devices
+            WHERE $1 = ANY(tags)
+            ORDER BY id
+            "#,
+            tag
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(devices)
+    }
+
+    pub async fn get_devices_by_os(
+        self: &Self,
+        os_family: String,
+    ) -> Result<Vec<Device>> {
+        let devices = sqlx::query_as!(
+            Device,
+            r#"
+            SELECT
+                id,
+                address,
+                port,
+                name,
+                username,
+                os_family,
+                os_version,
+                tags,
+                enabled,
+                credential_kind as "credential_kind: CredentialKind",
+                encrypted_password,
+                password_nonce,
+                encrypted_private_key,
+                private_key_nonce,
+                encrypted_passphrase,
+                passphrase_nonce
+            FROM devices
+            WHERE os_family = $1
+            ORDER BY id
+            "#,
+            os_family
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(devices)
+    }
+
+    /// Returns the devices carrying every one of `tags`, optionally
+    /// restricted to a single OS family.
+    pub async fn find_devices(
+        self: &Self,
+        tags: Vec<String>,
+        os_family: Option<String>,
+    ) -> Result<Vec<Device>> {
+        let devices = sqlx::query_as!(
+            Device,
+            r#"
+            SELECT
+                id,
+                address,
+                port,
+                name,
+                username,
+                os_family,
+                os_version,
+                tags,
+                enabled,
+                credential_kind as "credential_kind: CredentialKind",
+                encrypted_password,
+                password_nonce,
+                encrypted_private_key,
+                private_key_nonce,
+                encrypted_passphrase,
+                passphrase_nonce
+            FROM devices
+            WHERE tags @> $1 AND ($2::TEXT IS NULL OR os_family = $2)
+            ORDER BY id
+            "#,
+            &tags,
+            os_family
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(devices)
+    }
+
+    pub async fn update_device(
+        self: &Self,
+        id: i64,
+        device: NewDevice,
+        credential: Credential,
+    ) -> Result<Device> {
+        let enc = encrypt_credential(&self.cipher, &credential)?;
+        let device = sqlx::query_as!(
+            Device,
+            r#"
+            UPDATE devices
+            SET
+                address = $2,
+                port = $3,
+                name = $4,
+                username = $5,
+                tags = $6,
+                enabled = $7,
+                credential_kind = $8::credential_kind,
+                encrypted_password = $9,
+                password_nonce = $10,
+                encrypted_private_key = $11,
+                private_key_nonce = $12,
+                encrypted_passphrase = $13,
+                passphrase_nonce = $14
+            WHERE id = $1
+            RETURNING
+                id,
+                address,
+                port,
+                name,
+                username,
+                os_family,
+                os_version,
+                tags,
+                enabled,
+                credential_kind as "credential_kind: CredentialKind",
+                encrypted_password,
+                password_nonce,
+                encrypted_private_key,
+                private_key_nonce,
+                encrypted_passphrase,
+                passphrase_nonce
+            "#,
+            id,
+            device.address,
+            device.port,
+            device.name,
+            device.username,
+            &device.tags,
+            device.enabled,
+            credential.kind() as _,
+            enc.encrypted_password,
+            enc.password_nonce,
+            enc.encrypted_private_key,
+            enc.private_key_nonce,
+            enc.encrypted_passphrase,
+            enc.passphrase_nonce
+        )
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(device)
+    }
+
+    pub async fn update_device_os(
+        self: &Self,
+        id: i64,
+        os_family: String,
+        os_version: Option<String>,
+    ) -> Result<u64> {
+        let result = sqlx::query!(
+            r"UPDATE devices SET os_family = $2, os_version = $3 WHERE id = $1",
+            id,
+            os_family,
+            os_version
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected())
+    }
+
+    pub fn device_credential(
+        self: &Self,
+        device: &Device,
+    ) -> Result<Credential> {
+        decrypt_credential(&self.cipher, device)
+    }
+
+    /// Turns the enabled devices among `devices` into scan targets, along
+    /// with their rule parameter overrides, active waivers and groups.
+    pub async fn targets_for(
+        self: &Self,
+        devices: Vec<Device>,
+    ) -> Result<Vec<Target>> {
+        let devices: Vec<Device> = devices
+            .into_iter()
+            .filter(|device| device.enabled)
+            .collect();
+        let ids: Vec<i64> = devices.iter().map(|device| device.id).collect();
+        let mut params: HashMap<i64, HashMap<String, ParamValues>> =
+            HashMap::new();
+        for entry in self.get_params_for_devices(ids.clone()).await? {
+            params
+                .entry(entry.device_id)
+                .or_default()
+                .insert(entry.rule_id, entry.params.0);
+        }
+        let mut groups: HashMap<i64, Vec<String>> = HashMap::new();
+        for (device_id, name) in self.get_group_names(ids.clone()).await? {
+            groups.entry(device_id).or_default().push(name);
+        }
+        let mut waivers: HashMap<i64, HashMap<String, Waiver>> = HashMap::new();
+        for (device_id, waiver) in self.get_active_waivers(ids).await? {
+            waivers
+                .entry(device_id)
+                .or_default()
+                .insert(waiver.rule_id.clone(), waiver);
+        }
+
+        devices
+            .into_iter()
+            .map(|device| {
+                Ok(Target {
+                    credential: self.device_credential(&device)?,
+                    params: params.remove(&device.id).unwrap_or_default(),
+                    waivers: waivers.remove(&device.id).unwrap_or_default(),
+                    groups: groups.remove(&device.id).unwrap_or_default(),
+                    device_id: device.id,
+                    address: device.address,
+                    port: device.port as u16,
+                    username: device.username,
+                    os_family: device.os_family,
+                    os_version: device.os_version,
+                    os_override: false,
+                })
+            })
+            .collect()
+    }
+
+    /// Returns every enabled device along with its decrypted credential.
+    pub async fn get_all_targets(self: &Self) -> Result<Vec<Target>> {
+        self.targets_for(self.get_all_devices().await?).await
+    }
+
+    pub async fn remove_device(self: &Self, id: i64) -> Result<u64> {
+        let result = sqlx::query!(r"DELETE FROM devices WHERE id = $1", id)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    // --- RuleRevision CRUD ---
+
+    pub async fn get_rule_revisions(
+        self: &Self,
+        rule_id: String,
+    ) -> Result<Vec<RuleRevision>> {
+        let revisions = sqlx::query_as!(
+            RuleRevision,
+            r#"
+            SELECT
+                id,
+                rule_id,
+                version,
+                content_hash,
+                name,
+                description,
+                severity as "severity: SeverityLevel",
+                script_body,
+                os_families,
+                min_os_version,
+                max_os_version,
+                params as "params: Json<ParamSpecs>",
+                created_at
+            FROM rule_revisions
+            WHERE rule_id = $1
+            ORDER BY version
+            "#,
+            rule_id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(revisions)
+    }
+
+    pub async fn get_rule_revision(
+        self: &Self,
+        rule_id: String,
+        version: i32,
+    ) -> Result<Option<RuleRevision>> {
+        let revision = sqlx::query_as!(
+            RuleRevision,
+            r#"
+            SELECT
+                id,
+                rule_id,
+                version,
+                content_hash,
+                name,
+                description,
+                severity as "severity: SeverityLevel",
+                script_body,
+                os_families,
+                min_os_version,
+                max_os_version,
+                params as "params: Json<ParamSpecs>",
+                created_at
+            FROM rule_revisions
+            WHERE rule_id = $1 AND version = $2
+            "#,
+            rule_id,
+            version
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(revision)
+    }
+
+    /// Returns the revision of the rule that produced a scan result.
+    pub async fn get_revision_for_result(
+        self: &Self,
+        result_id: i64,
+    ) -> Result<Option<RuleRevision>> {
+        let revision = sqlx::query_as!(
+            RuleRevision,
+            r#"
+            SELECT
+                r.id,
+                r.rule_id,
+                r.version,
+                r.content_hash,
+                r.name,
+                r.description,
+                r.severity as "severity: SeverityLevel",
+                r.script_body,
+                r.os_families,
+                r.min_os_version,
+                r.max_os_version,
+                r.params as "params: Json<ParamSpecs>",
+                r.created_at
+            FROM rule_revisions r
+            JOIN scan_results s ON s.revision_id = r.id
+            WHERE s.id = $1
+            "#,
+            result_id
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(revision)
+    }
+
+    // --- DeviceRuleParams CRUD ---
+
+    pub async fn get_params_for_devices(
+        self: &Self,
+        device_ids: Vec<i64>,
+    ) -> Result<Vec<DeviceRuleParams>> {
+        let params = sqlx::query_as!(
+            DeviceRuleParams,
+            r#"
+            SELECT
+                device_id,
+                rule_id,
+                params as "params: Json<ParamValues>"
+            FROM device_rule_params
+            WHERE device_id = ANY($1)
+            ORDER BY device_id, rule_id
+            "#,
+            &device_ids
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(params)
+    }
+
+    pub async fn set_device_params(
+        self: &Self,
+        device_id: i64,
+        rule_id: String,
+        params: ParamValues,
+    ) -> Result<DeviceRuleParams> {
+        let result = sqlx::query_as!(
+            DeviceRuleParams,
+            r#"
+            INSERT INTO device_rule_params (device_id, rule_id, params)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (device_id, rule_id) DO UPDATE
+            SET params = EXCLUDED.params
+            RETURNING
+                device_id,
+                rule_id,
+                params as "params: Json<ParamValues>"
+            "#,
+            device_id,
+            rule_id,
+            Json(params) as _
+        )
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(result)
+    }
+
+    pub async fn remove_device_params(
+        self: &Self,
+        device_id: i64,
+        rule_id: String,
+    ) -> Result<u64> {
+        let result = sqlx::query!(
+            r"
+            DELETE FROM device_rule_params
+            WHERE device_id = $1 AND rule_id = $2
+            ",
+            device_id,
+            rule_id
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected())
+    }
+
+    // --- DeviceGroup CRUD ---
+
+    pub async fn get_all_groups(self: &Self) -> Result<Vec<DeviceGroup>> {
+        let groups = sqlx::query_as!(
+            DeviceGroup,
+            r#"
+            SELECT id, name, description
+            FROM device_groups
+            ORDER BY name
+            "#
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(groups)
+    }
+
+    pub async fn get_group_by_name(
+        self: &Self,
+        name: String,
+    ) -> Result<Option<DeviceGroup>> {
+        let group = sqlx::query_as!(
+            DeviceGroup,
+            r#"
+            SELECT id, name, description
+            FROM device_groups WHERE name = $1
+            "#,
+            name
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(group)
+    }
+
+    pub async fn add_group(
+        self: &Self,
+        name: String,
+        description: Option<String>,
+    ) -> Result<DeviceGroup> {
+        let result = sqlx::query_as!(
+            DeviceGroup,
+            r#"
+            INSERT INTO device_groups (name, description)
+            VALUES ($1, $2)
+            RETURNING id, name, description
+            "#,
+            name,
+            description
+        )
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(result)
+    }
+
+    pub async fn remove_group(self: &Self, id: i64) -> Result<u64> {
+        let result =
+            sqlx::query!(r"DELETE FROM device_groups WHERE id = $1", id)
+                .execute(&self.pool)
+                .await?;
+        Ok(result.rows_affected())
+    }
+
+    pub async fn add_group_member(
+        self: &Self,
+        group_id: i64,
+        device_id: i64,
+    ) -> Result<u64> {
+        let result = sqlx::query!(
+            r"
+            INSERT INTO device_group_members (group_id, device_id)
+            VALUES ($1, $2)
+            ON CONFLICT DO NOTHING
+            ",
+            group_id,
+            device_id
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected())
+    }
+
+    pub async fn remove_group_member(
+        self: &Self,
+        group_id: i64,
+        device_id: i64,
+    ) -> Result<u64> {
+        let result = sqlx::query!(
+            r"
+            DELETE FROM device_group_members
+            WHERE group_id = $1 AND device_id = $2
+            ",
+            group_id,
+            device_id
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected())
+    }
+
+    pub async fn get_group_devices(
+        self: &Self,
+        group_id: i64,
+    ) -> Result<Vec<Device>> {
+        let devices = sqlx::query_as!(
+            Device,
+            r#"
+            SELECT
+                d.id,
+                d.address,
+                d.port,
+                d.name,
+                d.username,
+                d.os_family,
+                d.os_version,
+                d.tags,
+                d.enabled,
+                d.credential_kind as "credential_kind: CredentialKind",
+                d.encrypted_password,
+                d.password_nonce,
+                d.encrypted_private_key,
+                d.private_key_nonce,
+                d.encrypted_passphrase,
+                d.passphrase_nonce
+            FROM devices d
+            JOIN device_group_members m ON m.device_id = d.id
+            WHERE m.group_id = $1
+            ORDER BY d.id
+            "#,
+            group_id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(devices)
+    }
+
+    pub async fn get_groups_for_device(
+        self: &Self,
+        device_id: i64,
+    ) -> Result<Vec<DeviceGroup>> {
+        let groups = sqlx::query_as!(
+            DeviceGroup,
+            r#"
+            SELECT g.id, g.name, g.description
+            FROM device_groups g
+            JOIN device_group_members m ON m.group_id = g.id
+            WHERE m.device_id = $1
+            ORDER BY g.name
+            "#,
+            device_id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(groups)
+    }
+
+    /// Returns the names of the groups each of `device_ids` belongs to, as
+    /// `(device_id, group_name)` pairs.
+    pub async fn get_group_names(
+        self: &Self,
+        device_ids: Vec<i64>,
+    ) -> Result<Vec<(i64, String)>> {
+        let rows = sqlx::query!(
+            r"
+            SELECT m.device_id, g.name
+            FROM device_group_members m
+            JOIN device_groups g ON g.id = m.group_id
+            WHERE m.device_id = ANY($1)
+            ORDER BY m.device_id, g.name
+            ",
+            &device_ids
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| (row.device_id, row.name))
+            .collect())
+    }
+
+    // --- HostKey CRUD ---
+
+    pub async fn get_all_host_keys(self: &Self) -> Result<Vec<PinnedHostKey>> {
+        let keys = sqlx::query_as!(
+            PinnedHostKey,
+            r#"
+            SELECT
+                id,
+                device_id,
+                algorithm,
+                public_key,
+                fingerprint,
+                status as "status: HostKeyStatus"
+            FROM host_keys
+            ORDER BY device_id, id
+            "#
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(keys)
+    }
+
+    pub async fn get_host_keys_for_device(
         self: &Self,
-        address: String,
-        username: String,
-        password: String,
-    ) -> Result<Device> {
-        let (nonce, enc_password) =
-            encrypt_password(&self.cipher, password.as_str())?;
-        let result = sqlx::query_as!(
-            Device,
+        device_id: i64,
+    ) -> Result<Vec<PinnedHostKey>> {
+        let keys = sqlx::query_as!(
+            PinnedHostKey,
             r#"
-            INSERT INTO devices (address, username, encrypted_password, password_nonce)
-            VALUES ($1, $2, $3, $4)
-            RETURNING id, address, username, encrypted_password, password_nonce
+            SELECT
+                id,
+                device_id,
+                algorithm,
+                public_key,
+                fingerprint,
+                status as "status: HostKeyStatus"
+            FROM host_keys
+            WHERE device_id = $1
+            ORDER BY id
             "#,
-            address,
-            username,
-            enc_password,
-            nonce
+            device_id
         )
-        .fetch_one(&self.pool)
+        .fetch_all(&self.pool)
         .await?;
-        Ok(result)
+        Ok(keys)
     }
 
-    pub async fn get_device(self: &Self, id: i64) -> Result<Option<Device>> {
-        let device = sqlx::query_as!(
-            Device,
+    /// Pins `key` for a device. A key already recorded as pending, such as
+    /// one left on first use, takes the new status; trusted and revoked
+    /// keys keep theirs, so a revocation sticks. The stored key is returned
+    /// either way.
+    pub async fn add_host_key(
+        self: &Self,
+        device_id: i64,
+        key: &HostKey,
+        status: HostKeyStatus,
+    ) -> Result<PinnedHostKey> {
+        let result = sqlx::query_as!(
+            PinnedHostKey,
             r#"
-            SELECT id, address, username, encrypted_password, password_nonce
-            FROM devices WHERE id = $1
+            INSERT INTO host_keys
+                (device_id, algorithm, public_key, fingerprint, status)
+            VALUES ($1, $2, $3, $4, $5::host_key_status)
+            ON CONFLICT (device_id, public_key) DO UPDATE SET
+                algorithm = EXCLUDED.algorithm,
+                fingerprint = EXCLUDED.fingerprint,
+                status = CASE
+                    WHEN host_keys.status = 'pending'::host_key_status
+                    THEN EXCLUDED.status
+                    ELSE host_keys.status
+                END
+            RETURNING
+                id,
+                device_id,
+                algorithm,
+                public_key,
+                fingerprint,
+                status as "status: HostKeyStatus"
             "#,
-            id
+            device_id,
+            key.algorithm,
+            key.public_key,
+            key.fingerprint,
+            status as _
         )
-        .fetch_optional(&self.pool)
+        .fetch_one(&self.pool)
         .await?;
-        Ok(device)
+        Ok(result)
     }
 
-    pub async fn update_device(
+    pub async fn update_host_key_status(
         self: &Self,
         id: i64,
-        address: String,
-        username: String,
-        password: String,
-    ) -> Result<Device> {
-        let (enc_password, nonce) =
-            encrypt_password(&self.cipher, password.as_str())?;
-        let device = sqlx::query_as!(
-            Device,
+        status: HostKeyStatus,
+    ) -> Result<PinnedHostKey> {
+        let result = sqlx::query_as!(
+            PinnedHostKey,
             r#"
-            UPDATE devices
-            SET
-                address = $2,
-                username = $3,
-                encrypted_password = $4,
-                password_nonce = $5
+            UPDATE host_keys SET status = $2::host_key_status
             WHERE id = $1
-            RETURNING id, address, username, encrypted_password, password_nonce
+            RETURNING
+                id,
+                device_id,
+                algorithm,
+                public_key,
+                fingerprint,
+                status as "status: HostKeyStatus"
             "#,
             id,
-            address,
-            username,
-            enc_password,
-            nonce
+            status as _
         )
         .fetch_one(&self.pool)
         .await?;
-        Ok(device)
+        Ok(result)
     }
 
-    pub async fn remove_device(self: &Self, id: i64) -> Result<u64> {
-        let result = sqlx::query!(r"DELETE FROM devices WHERE id = $1", id)
+    pub async fn remove_host_key(self: &Self, id: i64) -> Result<u64> {
+        let result = sqlx::query!(r"DELETE FROM host_keys WHERE id = $1", id)
             .execute(&self.pool)
             .await?;
         Ok(result.rows_affected())
@@ -120,13 +928,18 @@ impl Db {
         let rules = sqlx::query_as!(
             Rule,
             r#"
-            SELECT 
+            SELECT
                 id,
                 name,
                 description,
                 severity as "severity: SeverityLevel",
                 check_type as "check_type: CheckType",
-                script_body
+                script_body,
+                os_families,
+                min_os_version,
+                max_os_version,
+                params as "params: Json<ParamSpecs>",
+                revision_id
             FROM rules
             "#,
         )
@@ -145,7 +958,12 @@ impl Db {
                 description,
                 severity as "severity: SeverityLevel",
                 check_type as "check_type: CheckType",
-                script_body
+                script_body,
+                os_families,
+                min_os_version,
+                max_os_version,
+                params as "params: Json<ParamSpecs>",
+                revision_id
             FROM rules
             WHERE id = $1
             "#,
@@ -156,84 +974,354 @@ impl Db {
         Ok(result)
     }
 
-    pub async fn add_rule(
+    /// Adds a rule along with its first revision.
+    pub async fn add_rule(self: &Self, rule: Rule) -> Result<Rule> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query!(
+            r"
+            INSERT INTO rules (
+                id,
+                name,
+                description,
+                severity,
+                check_type,
+                script_body,
+                os_families,
+                min_os_version,
+                max_os_version,
+                params
+            )
+            VALUES (
+                $1, $2, $3, $4::severity_level, $5::check_type, $6, $7, $8, $9,
+                $10
+            )
+            ",
+            rule.id,
+            rule.name,
+            rule.description,
+            rule.severity.clone() as _,
+            rule.check_type.clone() as _,
+            rule.script_body,
+            &rule.os_families,
+            rule.min_os_version,
+            rule.max_os_version,
+            rule.params as _
+        )
+        .execute(&mut *tx)
+        .await?;
+        record_revision(&mut tx, &rule).await?;
+        tx.commit().await?;
+        self.get_rule(rule.id).await
+    }
+
+    /// Updates a rule, recording a new revision if its script changed.
+    pub async fn update_rule(self: &Self, rule: Rule) -> Result<Rule> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query!(
+            r"
+            UPDATE rules
+            SET
+                name = $2,
+                description = $3,
+                severity = $4::severity_level,
+                check_type = $5::check_type,
+                script_body = $6,
+                os_families = $7,
+                min_os_version = $8,
+                max_os_version = $9,
+                params = $10
+            WHERE id = $1
+            ",
+            rule.id,
+            rule.name,
+            rule.description,
+            rule.severity.clone() as _,
+            rule.check_type.clone() as _,
+            rule.script_body,
+            &rule.os_families,
+            rule.min_os_version,
+            rule.max_os_version,
+            rule.params as _
+        )
+        .execute(&mut *tx)
+        .await?;
+        record_revision(&mut tx, &rule).await?;
+        tx.commit().await?;
+        self.get_rule(rule.id).await
+    }
+
+    pub async fn remove_rule(self: &Self, id: String) -> Result<u64> {
+        let result = sqlx::query!(r"DELETE FROM rules WHERE id = $1", id)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    // --- Profile CRUD ---
+
+    pub async fn get_all_profiles(self: &Self) -> Result<Vec<Profile>> {
+        let profiles = sqlx::query_as!(
+            Profile,
+            r#"
+            SELECT id, name, description, parent_id
+            FROM profiles
+            ORDER BY name
+            "#
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(profiles)
+    }
+
+    pub async fn get_profile(self: &Self, id: i64) -> Result<Option<Profile>> {
+        let profile = sqlx::query_as!(
+            Profile,
+            r#"
+            SELECT id, name, description, parent_id
+            FROM profiles WHERE id = $1
+            "#,
+            id
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(profile)
+    }
+
+    pub async fn get_profile_by_name(
+        self: &Self,
+        name: String,
+    ) -> Result<Option<Profile>> {
+        let profile = sqlx::query_as!(
+            Profile,
+            r#"
+            SELECT id, name, description, parent_id
+            FROM profiles WHERE name = $1
+            "#,
+            name
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(profile)
+    }
+
+    pub async fn add_profile(
         self: &Self,
-        id: String,
         name: String,
         description: Option<String>,
-        severity: SeverityLevel,
-        check_type: CheckType,
-        script_body: String,
-    ) -> Result<Rule> {
+        parent_id: Option<i64>,
+    ) -> Result<Profile> {
         let result = sqlx::query_as!(
-            Rule,
+            Profile,
             r#"
-            INSERT INTO rules
-                (id, name, description, severity, check_type, script_body)
-            VALUES
-                ($1, $2, $3, $4::severity_level, $5::check_type, $6)
-            RETURNING
-                id, 
-                name, 
-                description, 
-                severity as "severity: SeverityLevel", 
-                check_type as "check_type: CheckType",
-                script_body
+            INSERT INTO profiles (name, description, parent_id)
+            VALUES ($1, $2, $3)
+            RETURNING id, name, description, parent_id
             "#,
-            id,
             name,
             description,
-            severity as _,
-            check_type as _,
-            script_body
+            parent_id
         )
         .fetch_one(&self.pool)
         .await?;
         Ok(result)
     }
 
-    pub async fn update_rule(
+    pub async fn remove_profile(self: &Self, id: i64) -> Result<u64> {
+        let result = sqlx::query!(r"DELETE FROM profiles WHERE id = $1", id)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    pub async fn get_profile_rules(
         self: &Self,
-        id: String,
-        name: String,
-        description: Option<String>,
-        severity: SeverityLevel,
-        check_type: CheckType,
-        script_body: String,
-    ) -> Result<Rule> {
+        profile_id: i64,
+    ) -> Result<Vec<ProfileRule>> {
+        let rules = sqlx::query_as!(
+            ProfileRule,
+            r#"
+            SELECT
+                profile_id,
+                rule_id,
+                severity as "severity: SeverityLevel",
+                excluded,
+                params as "params: Json<ParamValues>"
+            FROM profile_rules
+            WHERE profile_id = $1
+            ORDER BY rule_id
+            "#,
+            profile_id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rules)
+    }
+
+    /// Adds a rule to a profile, or replaces how the profile includes it.
+    pub async fn set_profile_rule(
+        self: &Self,
+        profile_id: i64,
+        rule_id: String,
+        severity: Option<SeverityLevel>,
+        excluded: bool,
+        params: ParamValues,
+    ) -> Result<ProfileRule> {
         let result = sqlx::query_as!(
-            Rule,
+            ProfileRule,
             r#"
-            UPDATE rules
+            INSERT INTO profile_rules
+                (profile_id, rule_id, severity, excluded, params)
+            VALUES ($1, $2, $3::severity_level, $4, $5)
+            ON CONFLICT (profile_id, rule_id) DO UPDATE
             SET
-                name = $2,
-                description = $3,
-                severity = $4::severity_level,
-                check_type = $5::check_type,
-                script_body = $6
-            WHERE id = $1
+                severity = EXCLUDED.severity,
+                excluded = EXCLUDED.excluded,
+                params = EXCLUDED.params
             RETURNING
-                id, 
-                name, 
-                description, 
-                severity as "severity: SeverityLevel", 
-                check_type as "check_type: CheckType",
-                script_body
+                profile_id,
+                rule_id,
+                severity as "severity: SeverityLevel",
+                excluded,
+                params as "params: Json<ParamValues>"
             "#,
-            id,
-            name,
-            description,
+            profile_id,
+            rule_id,
             severity as _,
-            check_type as _,
-            script_body
+            excluded,
+            Json(params) as _
         )
         .fetch_one(&self.pool)
         .await?;
         Ok(result)
     }
 
-    pub async fn remove_rule(self: &Self, id: String) -> Result<u64> {
-        let result = sqlx::query!(r"DELETE FROM rules WHERE id = $1", id)
+    pub async fn remove_profile_rule(
+        self: &Self,
+        profile_id: i64,
+        rule_id: String,
+    ) -> Result<u64> {
+        let result = sqlx::query!(
+            r"DELETE FROM profile_rules WHERE profile_id = $1 AND rule_id = $2",
+            profile_id,
+            rule_id
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected())
+    }
+
+    // --- Waiver CRUD ---
+
+    pub async fn get_all_waivers(self: &Self) -> Result<Vec<Waiver>> {
+        let waivers = sqlx::query_as!(
+            Waiver,
+            r#"
+            SELECT
+                id,
+                rule_id,
+                device_id,
+                group_id,
+                justification,
+                approver,
+                expires_at,
+                created_at
+            FROM waivers
+            ORDER BY id
+            "#
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(waivers)
+    }
+
+    /// Returns the unexpired waivers covering each of `device_ids`, either
+    /// directly or through a group, as `(device_id, waiver)` pairs.
+    pub async fn get_active_waivers(
+        self: &Self,
+        device_ids: Vec<i64>,
+    ) -> Result<Vec<(i64, Waiver)>> {
+        let rows = sqlx::query!(
+            r#"
+            SELECT
+                d.id as "target_id!",
+                w.id,
+                w.rule_id,
+                w.device_id,
+                w.group_id,
+                w.justification,
+                w.approver,
+                w.expires_at,
+                w.created_at
+            FROM waivers w
+            JOIN devices d ON d.id = w.device_id OR EXISTS (
+                SELECT 1 FROM device_group_members m
+                WHERE m.group_id = w.group_id AND m.device_id = d.id
+            )
+            WHERE d.id = ANY($1) AND w.expires_at > now()
+            ORDER BY w.expires_at
+            "#,
+            &device_ids
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        let waivers = rows
+            .into_iter()
+            .map(|row| {
+                let waiver = Waiver {
+                    id: row.id,
+                    rule_id: row.rule_id,
+                    device_id: row.device_id,
+                    group_id: row.group_id,
+                    justification: row.justification,
+                    approver: row.approver,
+                    expires_at: row.expires_at,
+                    created_at: row.created_at,
+                };
+                (row.target_id, waiver)
+            })
+            .collect();
+        Ok(waivers)
+    }
+
+    pub async fn add_waiver(
+        self: &Self,
+        rule_id: String,
+        device_id: Option<i64>,
+        group_id: Option<i64>,
+        justification: String,
+        approver: String,
+        expires_at: DateTime<Utc>,
+    ) -> Result<Waiver> {
+        let result = sqlx::query_as!(
+            Waiver,
+            r#"
+            INSERT INTO waivers
+                (rule_id, device_id, group_id, justification, approver, expires_at)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            RETURNING
+                id,
+                rule_id,
+                device_id,
+                group_id,
+                justification,
+                approver,
+                expires_at,
+                created_at
+            "#,
+            rule_id,
+            device_id,
+            group_id,
+            justification,
+            approver,
+            expires_at
+        )
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(result)
+    }
+
+    pub async fn remove_waiver(self: &Self, id: i64) -> Result<u64> {
+        let result = sqlx::query!(r"DELETE FROM waivers WHERE id = $1", id)
             .execute(&self.pool)
             .await?;
         Ok(result.rows_affected())
@@ -251,7 +1339,17 @@ impl Db {
             r#"
             INSERT INTO scans (device_id, status)
             VALUES ($1, $2::scan_status)
-            RETURNING id, device_id, status as "status: ScanStatus"
+            RETURNING
+                id,
+                device_id,
+                status as "status: ScanStatus",
+                failure_reason,
+                started_at,
+                finished_at,
+                connect_attempts,
+                cache_hits,
+                cache_misses,
+                facts as "facts: Json<Facts>"
             "#,
             device_id,
             status as _
@@ -265,7 +1363,17 @@ impl Db {
         let scan = sqlx::query_as!(
             Scan,
             r#"
-            SELECT id, device_id, status as "status: ScanStatus"
+            SELECT
+                id,
+                device_id,
+                status as "status: ScanStatus",
+                failure_reason,
+                started_at,
+                finished_at,
+                connect_attempts,
+                cache_hits,
+                cache_misses,
+                facts as "facts: Json<Facts>"
             FROM scans WHERE id = $1
             "#,
             id
@@ -283,9 +1391,29 @@ impl Db {
         let scan = sqlx::query_as!(
             Scan,
             r#"
-            UPDATE scans SET status = $2::scan_status
+            UPDATE scans
+            SET
+                status = $2::scan_status,
+                started_at = CASE
+                    WHEN $2::scan_status = 'running' THEN now()
+                    ELSE started_at
+                END,
+                finished_at = CASE
+                    WHEN $2::scan_status IN ('completed', 'failed') THEN now()
+                    ELSE finished_at
+                END
             WHERE id = $1
-            RETURNING id, device_id, status as "status: ScanStatus"
+            RETURNING
+                id,
+                device_id,
+                status as "status: ScanStatus",
+                failure_reason,
+                started_at,
+                finished_at,
+                connect_attempts,
+                cache_hits,
+                cache_misses,
+                facts as "facts: Json<Facts>"
             "#,
             id,
             status as _
@@ -295,6 +1423,111 @@ impl Db {
         Ok(scan)
     }
 
+    pub async fn fail_scan(
+        self: &Self,
+        id: i64,
+        failure_reason: String,
+    ) -> Result<Scan> {
+        let scan = sqlx::query_as!(
+            Scan,
+            r#"
+            UPDATE scans
+            SET
+                status = 'failed',
+                failure_reason = $2,
+                started_at = COALESCE(started_at, now()),
+                finished_at = now()
+            WHERE id = $1
+            RETURNING
+                id,
+                device_id,
+                status as "status: ScanStatus",
+                failure_reason,
+                started_at,
+                finished_at,
+                connect_attempts,
+                cache_hits,
+                cache_misses,
+                facts as "facts: Json<Facts>"
+            "#,
+            id,
+            failure_reason
+        )
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(scan)
+    }
+
+    pub async fn set_connect_attempts(
+        self: &Self,
+        id: i64,
+        attempts: i32,
+    ) -> Result<u64> {
+        let result = sqlx::query!(
+            r"UPDATE scans SET connect_attempts = $2 WHERE id = $1",
+            id,
+            attempts
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected())
+    }
+
+    pub async fn set_cache_stats(
+        self: &Self,
+        id: i64,
+        hits: i32,
+        misses: i32,
+    ) -> Result<u64> {
+        let result = sqlx::query!(
+            r"UPDATE scans SET cache_hits = $2, cache_misses = $3 WHERE id = $1",
+            id,
+            hits,
+            misses
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected())
+    }
+
+    pub async fn set_scan_facts(
+        self: &Self,
+        id: i64,
+        facts: &Facts,
+    ) -> Result<u64> {
+        let result = sqlx::query!(
+            r"UPDATE scans SET facts = $2 WHERE id = $1",
+            id,
+            Json(facts) as _
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected())
+    }
+
+    pub async fn get_all_scans(self: &Self) -> Result<Vec<Scan>> {
+        let scans = sqlx::query_as!(
+            Scan,
+            r#"
+            SELECT
+                id,
+                device_id,
+                status as "status: ScanStatus",
+                failure_reason,
+                started_at,
+                finished_at,
+                connect_attempts,
+                cache_hits,
+                cache_misses,
+                facts as "facts: Json<Facts>"
+            FROM scans ORDER BY id
+            "#
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(scans)
+    }
+
     pub async fn get_scans_for_device(
         self: &Self,
         device_id: i64,
@@ -302,8 +1535,18 @@ impl Db {
         let scans = sqlx::query_as!(
             Scan,
             r#"
-            SELECT id, device_id, status as "status: ScanStatus"
-            FROM scans WHERE device_id = $1
+            SELECT
+                id,
+                device_id,
+                status as "status: ScanStatus",
+                failure_reason,
+                started_at,
+                finished_at,
+                connect_attempts,
+                cache_hits,
+                cache_misses,
+                facts as "facts: Json<Facts>"
+            FROM scans WHERE device_id = $1 ORDER BY id
             "#,
             device_id
         )
@@ -314,24 +1557,68 @@ impl Db {
 
     // --- ScanResult CRUD ---
 
+    /// Counts the results recorded for a rule, which keep it from being
+    /// removed.
+    pub async fn count_results_for_rule(
+        self: &Self,
+        rule_id: String,
+    ) -> Result<i64> {
+        let count = sqlx::query_scalar!(
+            r#"SELECT COUNT(*) as "count!" FROM scan_results WHERE rule_id = $1"#,
+            rule_id
+        )
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(count)
+    }
+
     pub async fn add_scan_result(
         self: &Self,
         scan_id: i64,
-        rule_id: String,
-        status: CheckStatus,
-        details: Option<String>,
+        outcome: &RuleOutcome,
     ) -> Result<ScanResult> {
         let result = sqlx::query_as!(
             ScanResult,
             r#"
-            INSERT INTO scan_results (scan_id, rule_id, status, details)
-            VALUES ($1, $2, $3::check_status, $4)
-            RETURNING id, scan_id, rule_id, status as "status: CheckStatus", details
+            INSERT INTO scan_results (
+                scan_id,
+                rule_id,
+                severity,
+                status,
+                details,
+                executed_at,
+                duration_ms,
+                params,
+                waiver_id,
+                revision_id
+            )
+            VALUES (
+                $1, $2, $3::severity_level, $4::check_status, $5, $6, $7, $8,
+                $9, $10
+            )
+            RETURNING
+                id,
+                scan_id,
+                rule_id,
+                severity as "severity: SeverityLevel",
+                status as "status: CheckStatus",
+                details,
+                executed_at,
+                duration_ms,
+                params as "params: Json<ParamValues>",
+                waiver_id,
+                revision_id
             "#,
             scan_id,
-            rule_id,
-            status as _,
-            details
+            outcome.rule_id,
+            outcome.severity.clone() as _,
+            outcome.status.clone() as _,
+            outcome.details,
+            outcome.executed_at,
+            outcome.duration_ms,
+            Json(&outcome.params) as _,
+            outcome.waiver_id,
+            outcome.revision_id
         )
         .fetch_one(&self.pool)
         .await?;
@@ -345,8 +1632,20 @@ impl Db {
         let results = sqlx::query_as!(
             ScanResult,
             r#"
-            SELECT id, scan_id, rule_id, status as "status: CheckStatus", details
+            SELECT
+                id,
+                scan_id,
+                rule_id,
+                severity as "severity: SeverityLevel",
+                status as "status: CheckStatus",
+                details,
+                executed_at,
+                duration_ms,
+                params as "params: Json<ParamValues>",
+                waiver_id,
+                revision_id
             FROM scan_results WHERE scan_id = $1
+            ORDER BY id
             "#,
             scan_id
         )
@@ -355,3 +1654,71 @@ impl Db {
         Ok(results)
     }
 }
+
+/// Points `rule` at a revision matching its script and metadata, adding one
+/// when either differs from the latest revision.
+async fn record_revision(
+    tx: &mut Transaction<'_, Postgres>,
+    rule: &Rule,
+) -> Result<i64> {
+    let hash = rule.content_hash();
+    let latest = sqlx::query!(
+        r"
+        SELECT id, content_hash FROM rule_revisions
+        WHERE rule_id = $1
+        ORDER BY version DESC
+        LIMIT 1
+        ",
+        rule.id
+    )
+    .fetch_optional(&mut **tx)
+    .await?;
+
+    let revision_id = match latest {
+        Some(latest) if latest.content_hash == hash => latest.id,
+        _ => {
+            sqlx::query_scalar!(
+                r"
+                INSERT INTO rule_revisions (
+                    rule_id,
+                    version,
+                    content_hash,
+                    name,
+                    description,
+                    severity,
+                    script_body,
+                    os_families,
+                    min_os_version,
+                    max_os_version,
+                    params
+                )
+                SELECT $1, COALESCE(MAX(version), 0) + 1, $2, $3, $4,
+                    $5::severity_level, $6, $7, $8, $9, $10
+                FROM rule_revisions WHERE rule_id = $1
+                RETURNING id
+                ",
+                rule.id,
+                hash,
+                rule.name,
+                rule.description,
+                rule.severity.clone() as _,
+                rule.script_body,
+                &rule.os_families,
+                rule.min_os_version,
+                rule.max_os_version,
+                Json(&rule.params.0) as _
+            )
+            .fetch_one(&mut **tx)
+            .await?
+        }
+    };
+
+    sqlx::query!(
+        r"UPDATE rules SET revision_id = $2 WHERE id = $1",
+        rule.id,
+        revision_id
+    )
+    .execute(&mut **tx)
+    .await?;
+    Ok(revision_id)
+}
@@ -3,7 +3,9 @@ use aes_gcm::{AeadCore, Aes256Gcm, Key, KeyInit, Nonce};
 
 use sha2::{Digest, Sha256};
 
-use anyhow::Result;
+use anyhow::{Context, Result};
+
+use crate::db::models::{Credential, CredentialKind, Device};
 
 pub fn make_cipher(master_key: &str) -> Aes256Gcm {
     let mut hasher = Sha256::new();
@@ -38,3 +40,92 @@ pub fn decrypt_password(
     let utf8_string = String::from_utf8(plaintext)?;
     Ok(utf8_string)
 }
+
+/// Credential columns of the `devices` table, encrypted at rest.
+#[derive(Debug, Default)]
+pub struct EncryptedCredential {
+    pub encrypted_password: Option<Vec<u8>>,
+    pub password_nonce: Option<Vec<u8>>,
+    pub encrypted_private_key: Option<Vec<u8>>,
+    pub private_key_nonce: Option<Vec<u8>>,
+    pub encrypted_passphrase: Option<Vec<u8>>,
+    pub passphrase_nonce: Option<Vec<u8>>,
+}
+
+pub fn encrypt_credential(
+    cipher: &Aes256Gcm,
+    credential: &Credential,
+) -> Result<EncryptedCredential> {
+    let mut encrypted = EncryptedCredential::default();
+    match credential {
+        Credential::Password { password } => {
+            let (nonce, ciphertext) = encrypt_password(cipher, password)?;
+            encrypted.password_nonce = Some(nonce);
+            encrypted.encrypted_password = Some(ciphertext);
+        }
+        Credential::PrivateKey { key, passphrase } => {
+            let (nonce, ciphertext) = encrypt_password(cipher, key)?;
+            encrypted.private_key_nonce = Some(nonce);
+            encrypted.encrypted_private_key = Some(ciphertext);
+            if let Some(passphrase) = passphrase {
+                let (nonce, ciphertext) = encrypt_password(cipher, passphrase)?;
+                encrypted.passphrase_nonce = Some(nonce);
+                encrypted.encrypted_passphrase = Some(ciphertext);
+            }
+        }
+        Credential::Agent => (),
+    }
+    Ok(encrypted)
+}
+
+fn decrypt_column(
+    cipher: &Aes256Gcm,
+    nonce: &Option<Vec<u8>>,
+    ciphertext: &Option<Vec<u8>>,
+) -> Result<Option<String>> {
+    match (nonce, ciphertext) {
+        (Some(nonce), Some(ciphertext)) => Ok(Some(decrypt_password(
+            cipher,
+            nonce.clone(),
+            ciphertext.clone(),
+        )?)),
+        _ => Ok(None),
+    }
+}
+
+pub fn decrypt_credential(
+    cipher: &Aes256Gcm,
+    device: &Device,
+) -> Result<Credential> {
+    let credential = match device.credential_kind {
+        CredentialKind::Password => Credential::Password {
+            password: decrypt_column(
+                cipher,
+                &device.password_nonce,
+                &device.encrypted_password,
+            )?
+            .context(format!(
+                "Device '{}' has no stored password",
+                device.address
+            ))?,
+        },
+        CredentialKind::PrivateKey => Credential::PrivateKey {
+            key: decrypt_column(
+                cipher,
+                &device.private_key_nonce,
+                &device.encrypted_private_key,
+            )?
+            .context(format!(
+                "Device '{}' has no stored private key",
+                device.address
+            ))?,
+            passphrase: decrypt_column(
+                cipher,
+                &device.passphrase_nonce,
+                &device.encrypted_passphrase,
+            )?,
+        },
+        CredentialKind::Agent => Credential::Agent,
+    };
+    Ok(credential)
+}
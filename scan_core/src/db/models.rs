@@ -1,5 +1,9 @@
+use std::collections::{BTreeMap, HashMap};
+
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use sqlx::{FromRow, Type};
+use sha2::{Digest, Sha256};
+use sqlx::{FromRow, Type, types::Json};
 
 #[derive(Debug, Clone, PartialEq, Eq, Type, Serialize, Deserialize)]
 #[sqlx(type_name = "severity_level", rename_all = "lowercase")]
@@ -18,11 +22,38 @@ pub enum CheckType {
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Type, Serialize, Deserialize)]
-#[sqlx(type_name = "check_status", rename_all = "lowercase")]
+#[sqlx(type_name = "check_status", rename_all = "snake_case")]
 pub enum CheckStatus {
+    #[serde(alias = "pass")]
     Pass,
+    /// Degraded, but not failing.
+    #[serde(alias = "warn")]
+    Warn,
+    #[serde(alias = "fail")]
     Fail,
+    #[serde(alias = "error")]
     Error,
+    /// The rule chose not to run.
+    #[serde(alias = "skip")]
+    Skip,
+    #[serde(alias = "not_applicable")]
+    NotApplicable,
+    /// The result requires human review.
+    #[serde(alias = "manual")]
+    Manual,
+    /// A failure covered by an active waiver.
+    Waived,
+}
+
+impl CheckStatus {
+    /// Whether the rule actually assessed the device, and so whether the
+    /// result counts towards a compliance score.
+    pub fn is_assessed(self: &Self) -> bool {
+        matches!(
+            self,
+            CheckStatus::Pass | CheckStatus::Warn | CheckStatus::Fail
+        )
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Type, Serialize, Deserialize)]
@@ -34,16 +65,160 @@ pub enum ScanStatus {
     Failed,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq, Type, Serialize, Deserialize)]
+#[sqlx(type_name = "host_key_status", rename_all = "lowercase")]
+pub enum HostKeyStatus {
+    Pending,
+    Trusted,
+    Revoked,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Type, Serialize, Deserialize)]
+#[sqlx(type_name = "credential_kind", rename_all = "snake_case")]
+pub enum CredentialKind {
+    Password,
+    PrivateKey,
+    Agent,
+}
+
+/// The type of a rule parameter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ParamType {
+    String,
+    Number,
+    Integer,
+    Boolean,
+    List,
+}
+
+/// A tunable parameter a rule declares in its metadata.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ParamSpec {
+    #[serde(rename = "type")]
+    pub kind: ParamType,
+    pub default: serde_json::Value,
+    pub description: Option<String>,
+}
+
+/// Parameter declarations of a rule, by name.
+pub type ParamSpecs = BTreeMap<String, ParamSpec>;
+
+/// Parameter values, by name.
+pub type ParamValues = BTreeMap<String, serde_json::Value>;
+
+/// A decrypted device credential, ready to be handed to the SSH layer.
+#[derive(Clone, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Credential {
+    Password {
+        password: String,
+    },
+    PrivateKey {
+        key: String,
+        passphrase: Option<String>,
+    },
+    Agent,
+}
+
+impl Credential {
+    pub fn kind(self: &Self) -> CredentialKind {
+        match self {
+            Credential::Password { .. } => CredentialKind::Password,
+            Credential::PrivateKey { .. } => CredentialKind::PrivateKey,
+            Credential::Agent => CredentialKind::Agent,
+        }
+    }
+}
+
+// Secrets are never printed, only the kind of credential.
+impl std::fmt::Debug for Credential {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Credential::{:?}", self.kind())
+    }
+}
+
+/// The inventory fields of a device, as given when adding or updating it.
+#[derive(Debug, Clone, Deserialize)]
+pub struct NewDevice {
+    pub address: String,
+    pub port: i32,
+    pub name: Option<String>,
+    pub username: String,
+    pub tags: Vec<String>,
+    pub enabled: bool,
+}
+
 #[derive(Debug, Deserialize, FromRow, Clone)]
 pub struct Device {
     pub id: i64,
     pub address: String,
+    pub port: i32,
+    pub name: Option<String>,
+    pub username: String,
+    pub os_family: Option<String>,
+    pub os_version: Option<String>,
+    pub tags: Vec<String>,
+    pub enabled: bool,
+    pub credential_kind: CredentialKind,
+    pub encrypted_password: Option<Vec<u8>>,
+    pub password_nonce: Option<Vec<u8>>,
+    pub encrypted_private_key: Option<Vec<u8>>,
+    pub private_key_nonce: Option<Vec<u8>>,
+    pub encrypted_passphrase: Option<Vec<u8>>,
+    pub passphrase_nonce: Option<Vec<u8>>,
+}
+
+/// A device to scan, with its credential already decrypted.
+#[derive(Debug, Clone)]
+pub struct Target {
+    pub device_id: i64,
+    pub address: String,
+    pub port: u16,
     pub username: String,
-    pub encrypted_password: Vec<u8>,
-    pub password_nonce: Vec<u8>,
+    pub credential: Credential,
+    /// The OS recorded for the device. The OS detected during the scan
+    /// takes precedence unless `os_override` is set.
+    pub os_family: Option<String>,
+    pub os_version: Option<String>,
+    /// Whether the recorded OS was given explicitly, as in an ad hoc
+    /// config, and is used instead of the detected one.
+    pub os_override: bool,
+    /// Rule parameter overrides for this device, by rule id.
+    pub params: HashMap<String, ParamValues>,
+    /// Active waivers for this device, by rule id.
+    pub waivers: HashMap<String, Waiver>,
+    /// Names of the device groups the device belongs to.
+    pub groups: Vec<String>,
+}
+
+#[derive(Debug, FromRow, Clone)]
+pub struct DeviceGroup {
+    pub id: i64,
+    pub name: String,
+    pub description: Option<String>,
+}
+
+#[derive(Debug, FromRow, Clone)]
+pub struct PinnedHostKey {
+    pub id: i64,
+    pub device_id: i64,
+    pub algorithm: String,
+    pub public_key: String,
+    pub fingerprint: String,
+    pub status: HostKeyStatus,
+}
+
+/// A server host key as it appears in a known_hosts file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostKey {
+    pub algorithm: String,
+    /// Base64 encoded key blob, without the algorithm prefix.
+    pub public_key: String,
+    pub fingerprint: String,
 }
 
-#[derive(Debug, FromRow)]
+#[derive(Debug, FromRow, Clone)]
 pub struct Rule {
     pub id: String,
     pub name: String,
@@ -51,20 +226,170 @@ pub struct Rule {
     pub severity: SeverityLevel,
     pub check_type: CheckType,
     pub script_body: String,
+    /// OS families the rule applies to; empty for all of them.
+    pub os_families: Vec<String>,
+    pub min_os_version: Option<String>,
+    pub max_os_version: Option<String>,
+    pub params: Json<ParamSpecs>,
+    /// The current revision; `None` for rules not stored in the database.
+    pub revision_id: Option<i64>,
+}
+
+impl Rule {
+    /// Hashes every field a revision records. Rules with the same hash are
+    /// the same rule, so changing any of these fields, whether through the
+    /// script or not, makes a new revision.
+    pub fn content_hash(self: &Self) -> String {
+        let content = serde_json::json!({
+            "name": self.name,
+            "description": self.description,
+            "severity": self.severity,
+            "script_body": self.script_body,
+            "os_families": self.os_families,
+            "min_os_version": self.min_os_version,
+            "max_os_version": self.max_os_version,
+            "params": self.params.0,
+        });
+        Sha256::digest(content.to_string().as_bytes())
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+}
+
+/// An immutable snapshot of a rule, as it was when scan results referencing
+/// it were produced.
+#[derive(Debug, FromRow, Clone, Serialize)]
+pub struct RuleRevision {
+    pub id: i64,
+    pub rule_id: String,
+    pub version: i32,
+    /// SHA-256 of the script and metadata, hex encoded.
+    pub content_hash: String,
+    pub name: String,
+    pub description: Option<String>,
+    pub severity: SeverityLevel,
+    pub script_body: String,
+    pub os_families: Vec<String>,
+    pub min_os_version: Option<String>,
+    pub max_os_version: Option<String>,
+    pub params: Json<ParamSpecs>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, FromRow, Clone)]
+pub struct Profile {
+    pub id: i64,
+    pub name: String,
+    pub description: Option<String>,
+    pub parent_id: Option<i64>,
+}
+
+/// A rule's membership in a profile.
+#[derive(Debug, FromRow, Clone)]
+pub struct ProfileRule {
+    pub profile_id: i64,
+    pub rule_id: String,
+    /// Replaces the rule's own severity within the profile.
+    pub severity: Option<SeverityLevel>,
+    pub excluded: bool,
+    /// Overrides the defaults of the rule's parameters within the profile.
+    pub params: Json<ParamValues>,
+}
+
+/// A device's overrides of one rule's parameters.
+#[derive(Debug, FromRow, Clone)]
+pub struct DeviceRuleParams {
+    pub device_id: i64,
+    pub rule_id: String,
+    pub params: Json<ParamValues>,
+}
+
+/// An accepted risk: failures of a rule on a device, or on every device of
+/// a group, are recorded as waived until the waiver expires.
+#[derive(Debug, FromRow, Clone, Serialize)]
+pub struct Waiver {
+    pub id: i64,
+    pub rule_id: String,
+    pub device_id: Option<i64>,
+    pub group_id: Option<i64>,
+    pub justification: String,
+    pub approver: String,
+    pub expires_at: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// The operating system a device runs, as detected over SSH.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OsInfo {
+    /// Lowercase OS identifier, such as `ubuntu` or `netbsd`.
+    pub family: String,
+    pub version: Option<String>,
+}
+
+/// What the scanner learns about a device before running any rule. Rules
+/// read it from the read-only `facts` global instead of probing themselves.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Facts {
+    pub os: Option<OsInfo>,
+    /// Every field of `/etc/os-release`, on systems that have one.
+    pub os_release: BTreeMap<String, String>,
+    /// Kernel release, as printed by `uname -r`.
+    pub kernel: Option<String>,
+    pub hostname: Option<String>,
+    /// Machine hardware name, such as `x86_64` or `aarch64`.
+    pub architecture: Option<String>,
+    /// The first package manager found, such as `apt`, `dnf` or `pkg`.
+    pub package_manager: Option<String>,
+    /// The name of process 1, such as `systemd` or `init`.
+    pub init_system: Option<String>,
+    /// Global unicast addresses of the device's interfaces.
+    pub ips: Vec<String>,
 }
 
-#[derive(Debug, FromRow)]
+#[derive(Debug, FromRow, Serialize)]
 pub struct Scan {
     pub id: i64,
     pub device_id: i64,
     pub status: ScanStatus,
+    pub failure_reason: Option<String>,
+    pub started_at: Option<DateTime<Utc>>,
+    pub finished_at: Option<DateTime<Utc>>,
+    pub connect_attempts: i32,
+    pub cache_hits: i32,
+    pub cache_misses: i32,
+    /// Facts gathered from the device when the scan connected.
+    pub facts: Option<Json<Facts>>,
 }
 
-#[derive(Debug, FromRow)]
+#[derive(Debug, FromRow, Serialize)]
 pub struct ScanResult {
     pub id: i64,
     pub scan_id: i64,
     pub rule_id: String,
+    pub severity: SeverityLevel,
+    pub status: CheckStatus,
+    pub details: Option<String>,
+    pub executed_at: DateTime<Utc>,
+    pub duration_ms: i64,
+    pub params: Json<ParamValues>,
+    pub waiver_id: Option<i64>,
+    pub revision_id: Option<i64>,
+}
+
+/// The outcome of running one rule against a device.
+#[derive(Debug, Clone, Serialize)]
+pub struct RuleOutcome {
+    pub rule_id: String,
+    pub severity: SeverityLevel,
     pub status: CheckStatus,
     pub details: Option<String>,
+    pub executed_at: DateTime<Utc>,
+    pub duration_ms: i64,
+    /// The parameter values the rule ran with.
+    pub params: ParamValues,
+    /// The waiver that turned a failure into `Waived`.
+    pub waiver_id: Option<i64>,
+    /// The revision of the rule that ran.
+    pub revision_id: Option<i64>,
 }
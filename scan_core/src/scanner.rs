@@ -1,19 +1,41 @@
+pub mod applicability;
+pub mod facts;
+pub mod host_keys;
+pub mod limits;
 pub mod lua;
+pub mod params;
+pub mod profiles;
+pub mod retry;
+pub mod sink;
 pub mod ssh;
+pub mod targets;
 
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use anyhow::Result;
-use mlua::{Function, Lua, LuaSerdeExt, Value};
+use anyhow::{Context, Result, anyhow, bail};
+use chrono::Utc;
+use futures::stream::{self, StreamExt};
+use mlua::{Lua, LuaSerdeExt, MultiValue, SerializeOptions, UserData, Value};
 use serde::Deserialize;
 use tokio::task::JoinHandle;
+use tokio::time::timeout;
 
-use crate::db::crypto::decrypt_password;
-use crate::{db::Db, scanner::lua::init_lua};
-use crate::{
-    db::models::{CheckStatus, ScanStatus},
-    scanner::ssh::SSHSession,
+use crate::db::models::{
+    CheckStatus, Facts, OsInfo, ParamValues, Rule, RuleOutcome, ScanStatus,
+    Target,
 };
+use crate::scanner::applicability::Applicability;
+use crate::scanner::facts::gather_facts;
+use crate::scanner::host_keys::HostKeyStore;
+use crate::scanner::limits::{ConcurrencyLimits, Scheduler};
+use crate::scanner::lua::{LuaLimits, init_lua, load_checks, read_only};
+use crate::scanner::params::resolve_params;
+use crate::scanner::profiles::RuleSource;
+use crate::scanner::retry::RetryPolicy;
+use crate::scanner::sink::ResultSink;
+use crate::scanner::ssh::{SSHSession, fetch_host_key};
+use crate::scanner::targets::{ScanScope, TargetSource};
 
 #[derive(Debug, Deserialize)]
 struct CheckResult {
@@ -21,97 +43,572 @@ struct CheckResult {
     details: Option<String>,
 }
 
+/// Limits applied while scanning, so that one unresponsive device or rule
+/// cannot stall the rest of the scan.
+#[derive(Debug, Clone)]
+pub struct ScanOptions {
+    /// Time allowed to open an SSH connection.
+    pub connect_timeout: Duration,
+    /// Time allowed for a single command run by a rule.
+    pub command_timeout: Duration,
+    /// Wall-clock time allowed for a whole rule, commands included.
+    pub rule_timeout: Duration,
+    /// Memory and instruction limits of each rule's Lua sandbox.
+    pub lua_limits: LuaLimits,
+    /// Caps on devices scanned and commands run at the same time.
+    pub concurrency: ConcurrencyLimits,
+    /// Retries of connections and commands after transient SSH failures.
+    pub retry: RetryPolicy,
+    /// Reuses the output of a command already run on the device during the
+    /// same scan. Rules opt out per command with `{ cache = false }`.
+    pub cache_commands: bool,
+}
+
+impl Default for ScanOptions {
+    fn default() -> Self {
+        Self {
+            connect_timeout: Duration::from_secs(10),
+            command_timeout: Duration::from_secs(60),
+            rule_timeout: Duration::from_secs(300),
+            lua_limits: LuaLimits::default(),
+            concurrency: ConcurrencyLimits::default(),
+            retry: RetryPolicy::default(),
+            cache_commands: true,
+        }
+    }
+}
+
 pub struct Scanner {
-    lua: Lua,
-    pub db: Db,
+    sink: Arc<dyn ResultSink>,
+    host_keys: Arc<dyn HostKeyStore>,
+    options: ScanOptions,
 }
 
 impl Scanner {
-    pub fn new(db: Db) -> Result<Self> {
-        let lua = init_lua()?;
-        Ok(Self { lua, db })
+    pub fn new(
+        sink: Arc<dyn ResultSink>,
+        host_keys: Arc<dyn HostKeyStore>,
+    ) -> Self {
+        Self {
+            sink,
+            host_keys,
+            options: ScanOptions::default(),
+        }
     }
 
-    pub async fn run(self: &Self) -> Result<()> {
-        let rules = self.db.get_all_rules().await?;
-        let devices = self.db.get_all_devices().await?;
+    pub fn with_options(mut self, options: ScanOptions) -> Self {
+        self.options = options;
+        self
+    }
 
-        let lua = Arc::new(self.lua.clone());
-        let db = Arc::new(self.db.clone());
-        let rules = Arc::new(rules);
+    /// Scans the devices `scope` resolves to through `source`.
+    pub async fn run_scope(
+        self: &Self,
+        rules: Vec<Rule>,
+        source: &dyn TargetSource,
+        scope: &ScanScope,
+    ) -> Result<()> {
+        let targets = source.targets(scope).await?;
+        if targets.is_empty() {
+            bail!("No enabled devices to scan in {:?}", scope);
+        }
+        self.run(rules, targets).await
+    }
 
+    /// Runs the rules of a compliance profile against the devices `scope`
+    /// resolves to.
+    pub async fn run_profile(
+        self: &Self,
+        profile: &str,
+        rules: &dyn RuleSource,
+        targets: &dyn TargetSource,
+        scope: &ScanScope,
+    ) -> Result<()> {
+        let rules = rules.profile_rules(profile).await?;
+        if rules.is_empty() {
+            bail!("Profile '{}' has no rules", profile);
+        }
+        self.run_scope(rules, targets, scope).await
+    }
+
+    /// Scans an explicit list of targets.
+    pub async fn run(
+        self: &Self,
+        rules: Vec<Rule>,
+        targets: Vec<Target>,
+    ) -> Result<()> {
+        let ctx = ScanContext {
+            sink: self.sink.clone(),
+            host_keys: self.host_keys.clone(),
+            rules: Arc::new(rules),
+            options: self.options.clone(),
+            scheduler: Arc::new(Scheduler::new(
+                self.options.concurrency.clone(),
+            )),
+        };
+
+        // A device whose scan cannot be recorded is skipped, so the scans
+        // already started are still awaited and their outcomes recorded.
         let mut handles = Vec::new();
-        for device in devices {
-            let lua = lua.clone();
-            let db = db.clone();
-            let rules = rules.clone();
-            let device = device.clone();
-
-            let password = decrypt_password(
-                &self.db.cipher,
-                device.password_nonce,
-                device.encrypted_password,
-            )?;
+        let mut unrecorded = Vec::new();
+        for target in targets {
+            let scan_id =
+                match self.sink.add_scan(&target, ScanStatus::Pending).await {
+                    Ok(scan_id) => scan_id,
+                    Err(e) => {
+                        eprintln!(
+                            "Could not record scan of '{}': {:#}",
+                            target.address, e
+                        );
+                        unrecorded.push(target.address);
+                        continue;
+                    }
+                };
+            let ctx = ctx.clone();
 
             let handle: JoinHandle<Result<()>> =
                 tokio::task::spawn(async move {
-                    let session = SSHSession::new(
-                        device.address.as_str(),
-                        device.username.as_str(),
-                        password.as_str(),
-                    )
-                    .await?;
-
-                    lua.globals().set("conn", session)?;
-
-                    let scan =
-                        db.add_scan(device.id, ScanStatus::Running).await?;
-                    for rule in rules.iter() {
-                        let rule_result = async {
-                            lua.load(&rule.script_body).exec()?;
-                            let func: Function =
-                                lua.globals().get("run_check")?;
-                            let table: Value = func.call_async(()).await?;
-                            let result: CheckResult = lua.from_value(table)?;
-
-                            db.add_scan_result(
-                                scan.id,
-                                rule.id.clone(),
-                                result.status.clone(),
-                                result.details.clone(),
-                            )
-                            .await?;
-                            println!("Result: {:?}", &result);
-                            anyhow::Ok(())
-                        }
-                        .await;
-                        match rule_result {
-                            Ok(_) => (),
-                            Err(e) => {
-                                db.add_scan_result(
-                                    scan.id,
-                                    rule.id.clone(),
-                                    CheckStatus::Error,
-                                    Some(format!(
-                                        "Rule execution failed: {}",
-                                        e
-                                    )),
+                    match ctx.scan_target(&target, scan_id).await {
+                        Ok(()) => {
+                            ctx.sink
+                                .update_scan_status(
+                                    scan_id,
+                                    ScanStatus::Completed,
                                 )
-                                .await?;
-                            }
-                        };
+                                .await
+                        }
+                        Err(e) => {
+                            eprintln!(
+                                "Scan of '{}' failed: {:#}",
+                                target.address, e
+                            );
+                            ctx.sink
+                                .fail_scan(scan_id, &format!("{:#}", e))
+                                .await
+                        }
                     }
-                    db.update_scan_status(scan.id, ScanStatus::Completed)
-                        .await?;
-                    Ok(())
                 });
-            handles.push(handle);
+            handles.push((scan_id, handle));
+        }
+
+        // A failing device must never take the other devices' scans with it.
+        for (scan_id, handle) in handles {
+            let reason = match handle.await {
+                Ok(Ok(())) => continue,
+                Ok(Err(e)) => format!("Failed to record scan: {:#}", e),
+                Err(e) => format!("Scan task failed: {}", e),
+            };
+            eprintln!("{}", reason);
+            if let Err(e) = self.sink.fail_scan(scan_id, &reason).await {
+                eprintln!("Failed to mark scan {} as failed: {:#}", scan_id, e);
+            }
+        }
+
+        if !unrecorded.is_empty() {
+            bail!("Could not record the scans of {}", unrecorded.join(", "));
         }
+        Ok(())
+    }
+}
+
+/// State shared by the tasks scanning each device.
+#[derive(Clone)]
+struct ScanContext {
+    sink: Arc<dyn ResultSink>,
+    host_keys: Arc<dyn HostKeyStore>,
+    rules: Arc<Vec<Rule>>,
+    options: ScanOptions,
+    scheduler: Arc<Scheduler>,
+}
 
-        for handle in handles {
-            handle.await??;
+impl ScanContext {
+    /// Connects to `target`, retrying transient failures as the retry policy
+    /// allows. Returns the session along with the attempts opening it took,
+    /// or those fetching the host key took if that failed first. The probe
+    /// and the session each take one attempt when nothing goes wrong, so
+    /// counting both would make every device look flaky.
+    async fn connect(
+        self: &Self,
+        target: &Target,
+    ) -> (Result<SSHSession>, u32) {
+        let options = &self.options;
+        let (host_key, probes) = options
+            .retry
+            .run(|| async {
+                self.scheduler.throttle_connect().await;
+                fetch_host_key(
+                    target.address.as_str(),
+                    target.port,
+                    options.connect_timeout,
+                )
+                .await
+            })
+            .await;
+        let host_key = match host_key {
+            Ok(host_key) => host_key,
+            Err(e) => return (Err(e), probes),
+        };
+        if let Err(e) = self.host_keys.verify(target, &host_key).await {
+            return (Err(e), probes);
         }
 
+        let (session, attempts) = options
+            .retry
+            .run(|| async {
+                self.scheduler.throttle_connect().await;
+                SSHSession::new(
+                    target.address.as_str(),
+                    target.port,
+                    target.username.as_str(),
+                    &target.credential,
+                    &host_key,
+                    options,
+                )
+                .await
+            })
+            .await;
+        (session, attempts)
+    }
+
+    /// Connects to `target` and runs every rule against it. Any error
+    /// returned here fails the scan as a whole.
+    async fn scan_target(
+        self: &Self,
+        target: &Target,
+        scan_id: i64,
+    ) -> Result<()> {
+        let options = &self.options;
+        let _permits = self.scheduler.acquire(target).await?;
+        // Running from here on, so connect time counts towards the scan and
+        // scans failing to connect still have a start.
+        self.sink
+            .update_scan_status(scan_id, ScanStatus::Running)
+            .await?;
+
+        let (session, attempts) = self.connect(target).await;
+        self.sink.set_connect_attempts(scan_id, attempts).await?;
+        let session = session
+            .context(format!(
+                "Connection failed after {} attempt(s)",
+                attempts
+            ))?
+            .for_scan(options.cache_commands);
+
+        let facts = gather_facts(&session).await;
+        self.sink.set_facts(scan_id, &facts).await?;
+        let recorded = target.os_family.as_ref().map(|family| OsInfo {
+            family: family.clone(),
+            version: target.os_version.clone(),
+        });
+        let os = match (recorded, &facts.os) {
+            (Some(recorded), _) if target.os_override => Some(recorded),
+            // The detected OS wins, so upgrades and reinstalls are picked up
+            // and applicability matches what rules see in `facts.os`.
+            (recorded, Some(os)) => {
+                if recorded.as_ref() != Some(os) {
+                    self.sink.update_device_os(scan_id, target, os).await?;
+                }
+                Some(os.clone())
+            }
+            (Some(recorded), None) => Some(recorded),
+            (None, None) => {
+                eprintln!("Could not detect OS of '{}'", target.address);
+                None
+            }
+        };
+
+        // Rules run concurrently up to `rules_per_device`; each one's
+        // commands go over channels of their own on the shared connection.
+        // Rules are passed by index: a closure taking `&Rule` would make the
+        // spawned scan's future fail the `Send` check.
+        let mut checks = stream::iter(0..self.rules.len())
+            .map(|i| {
+                let rule = &self.rules[i];
+                self.check_rule(target, &session, &facts, os.as_ref(), rule)
+            })
+            .buffer_unordered(options.concurrency.rules_per_device.max(1));
+        while let Some(outcome) = checks.next().await {
+            self.sink.add_result(scan_id, &outcome).await?;
+        }
+        drop(checks);
+
+        if session.reconnects() > 0 {
+            eprintln!(
+                "Reconnected to '{}' {} time(s) during the scan",
+                target.address,
+                session.reconnects()
+            );
+        }
+        let stats = session.cache_stats();
+        if options.cache_commands {
+            eprintln!(
+                "Command cache for '{}': {} hit(s), {} miss(es)",
+                target.address, stats.hits, stats.misses
+            );
+        }
+        self.sink.set_cache_stats(scan_id, stats).await?;
         Ok(())
     }
+
+    /// Runs one rule against `target`, turning any failure into an `Error`
+    /// outcome so it never stops the other rules.
+    async fn check_rule(
+        self: &Self,
+        target: &Target,
+        session: &SSHSession,
+        facts: &Facts,
+        os: Option<&OsInfo>,
+        rule: &Rule,
+    ) -> RuleOutcome {
+        let options = &self.options;
+        let session = session.for_rule();
+        let executed_at = Utc::now();
+        let start = Instant::now();
+        let mismatch = Applicability::of(rule).mismatch(os);
+        let params = resolve_params(&rule.params, target.params.get(&rule.id));
+        let result = match (mismatch, &params) {
+            (Some(reason), _) => Ok(Ok(CheckResult {
+                status: CheckStatus::NotApplicable,
+                details: Some(reason),
+            })),
+            (None, Err(e)) => Ok(Err(anyhow!("{:#}", e))),
+            (None, Ok(params)) => {
+                timeout(
+                    options.rule_timeout,
+                    run_rule(
+                        session.clone(),
+                        rule,
+                        params,
+                        facts,
+                        &options.lua_limits,
+                    ),
+                )
+                .await
+            }
+        };
+        let retries = session.retries();
+        let result = match result {
+            Ok(Ok(result)) => result,
+            Ok(Err(e)) => CheckResult {
+                status: CheckStatus::Error,
+                details: Some(format!("Rule execution failed: {}", e)),
+            },
+            Err(_) => CheckResult {
+                status: CheckStatus::Error,
+                details: Some(format!(
+                    "Rule timed out after {}s",
+                    options.rule_timeout.as_secs()
+                )),
+            },
+        };
+        let details = result.details.filter(|details| !details.is_empty());
+        let details = match (retries, details) {
+            (0, details) => details,
+            (n, Some(details)) => {
+                Some(format!("{}; commands retried {} time(s)", details, n))
+            }
+            (n, None) => Some(format!("Commands retried {} time(s)", n)),
+        };
+        let (status, waiver_id) = waive(target, rule, result.status);
+        RuleOutcome {
+            rule_id: rule.id.clone(),
+            severity: rule.severity.clone(),
+            status,
+            details,
+            executed_at,
+            duration_ms: start.elapsed().as_millis() as i64,
+            params: params.unwrap_or_default(),
+            waiver_id,
+            revision_id: rule.revision_id,
+        }
+    }
+}
+
+/// Records failures and warnings covered by an unexpired waiver as waived.
+/// The rule has still run, so its details are kept.
+fn waive(
+    target: &Target,
+    rule: &Rule,
+    status: CheckStatus,
+) -> (CheckStatus, Option<i64>) {
+    match (&status, target.waivers.get(&rule.id)) {
+        (CheckStatus::Fail | CheckStatus::Warn, Some(waiver))
+            if waiver.expires_at > Utc::now() =>
+        {
+            (CheckStatus::Waived, Some(waiver.id))
+        }
+        _ => (status, None),
+    }
+}
+
+/// Runs `rule` in a Lua state of its own, so neither `conn` nor anything the
+/// rule defines can leak into rules running for this or any other device.
+/// `conn` is the device's `SSHSession` outside of tests.
+async fn run_rule<C: UserData + Send + 'static>(
+    conn: C,
+    rule: &Rule,
+    params: &ParamValues,
+    facts: &Facts,
+    limits: &LuaLimits,
+) -> Result<CheckResult> {
+    let lua = init_lua(limits)?;
+    let conn = lua.create_userdata(conn)?;
+    lua.globals().set("conn", conn.clone())?;
+    lua.globals().set("params", lua.to_value(params)?)?;
+    // Missing facts are nil rather than `null`, so `if facts.kernel` works.
+    let no_nulls = SerializeOptions::new().serialize_none_to_null(false);
+    let facts = lua.to_value_with(facts, no_nulls)?;
+    lua.globals().set("facts", read_only(&lua, facts)?)?;
+
+    let check = load_checks(&lua, &rule.script_body, &rule.id)?
+        .into_iter()
+        .find(|check| check.metadata.id == rule.id)
+        .context(format!("Script does not define rule '{}'", rule.id))?;
+    if let Some(applies) = check.applies {
+        let applies: bool = applies.call_async(conn.clone()).await?;
+        if !applies {
+            return Ok(CheckResult {
+                status: CheckStatus::NotApplicable,
+                details: Some("Rule does not apply to this device".to_string()),
+            });
+        }
+    }
+    let values: MultiValue = check.run.call_async(conn).await?;
+    check_result(&lua, values)
+}
+
+/// Interprets what a check returned: either a `{ status, details }` table,
+/// `ok, details` as returned by `register_check` callbacks, or
+/// `status, details` with a status name such as `"warn"` or `"manual"`.
+/// Rules that do not apply should say so through `applies_to` or `applies`.
+fn check_result(lua: &Lua, values: MultiValue) -> Result<CheckResult> {
+    let mut values = values.into_iter();
+    let result = match values.next() {
+        Some(table @ Value::Table(_)) => lua.from_value(table)?,
+        first => {
+            let status = match first {
+                Some(Value::Boolean(true)) => CheckStatus::Pass,
+                Some(Value::Boolean(false)) => CheckStatus::Fail,
+                Some(status @ Value::String(_)) => lua
+                    .from_value(status)
+                    .context("Check returned an unknown status")?,
+                other => bail!(
+                    "Check returned {}, expected a boolean, a status or a table",
+                    other.map_or("nothing", |v| v.type_name())
+                ),
+            };
+            let details: Option<String> =
+                lua.from_value(values.next().unwrap_or(Value::Nil))?;
+            CheckResult { status, details }
+        }
+    };
+    // Only the scanner decides these, from the waivers and applicability
+    // it knows of; a rule claiming them is an error.
+    if let CheckStatus::Waived | CheckStatus::NotApplicable = result.status {
+        bail!(
+            "Check returned {:?}, which only the scanner may record",
+            result.status
+        );
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scanner::lua::rules_from_script;
+    use mlua::UserDataMethods;
+
+    /// Stands in for a device's SSH session: every command prints the
+    /// device's name, after a pause that lets other rules run meanwhile.
+    struct FakeConn(String);
+
+    impl UserData for FakeConn {
+        fn add_methods<M: UserDataMethods<Self>>(methods: &mut M) {
+            methods.add_async_method(
+                "run_cmd",
+                |_, conn, _command: String| async move {
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                    Ok(conn.0.clone())
+                },
+            );
+        }
+    }
+
+    const ISOLATION_SCRIPT: &str = r#"
+METADATA = { id = "ISO-1", name = "Isolation", severity = "Info" }
+
+function run_check()
+    local first = conn:run_cmd("hostname")
+    local second = conn:run_cmd("hostname")
+    if first == second then
+        return "Pass", first
+    end
+    return "Fail", first .. " then " .. second
+end
+"#;
+
+    async fn check_device(name: &str) -> CheckResult {
+        let rule = rules_from_script(ISOLATION_SCRIPT, "iso.lua")
+            .unwrap()
+            .remove(0);
+        run_rule(
+            FakeConn(name.to_string()),
+            &rule,
+            &ParamValues::new(),
+            &Facts::default(),
+            &LuaLimits::default(),
+        )
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn concurrent_devices_never_see_each_others_conn() {
+        let (a, b) = tokio::join!(check_device("web-1"), check_device("db-1"));
+        assert_eq!(a.status, CheckStatus::Pass);
+        assert_eq!(a.details.as_deref(), Some("web-1"));
+        assert_eq!(b.status, CheckStatus::Pass);
+        assert_eq!(b.details.as_deref(), Some("db-1"));
+    }
+
+    fn returned(code: &str) -> Result<CheckResult> {
+        let lua = Lua::new();
+        let values: MultiValue = lua.load(code).eval().unwrap();
+        check_result(&lua, values)
+    }
+
+    #[test]
+    fn check_result_reads_each_return_convention() {
+        let result = returned("return true, 'ufw installed'").unwrap();
+        assert_eq!(result.status, CheckStatus::Pass);
+        assert_eq!(result.details.as_deref(), Some("ufw installed"));
+
+        let result = returned("return false").unwrap();
+        assert_eq!(result.status, CheckStatus::Fail);
+        assert_eq!(result.details, None);
+
+        let result = returned("return 'warn', 'degraded'").unwrap();
+        assert_eq!(result.status, CheckStatus::Warn);
+
+        let result =
+            returned("return { status = 'Manual', details = 'review' }")
+                .unwrap();
+        assert_eq!(result.status, CheckStatus::Manual);
+        assert_eq!(result.details.as_deref(), Some("review"));
+    }
+
+    #[test]
+    fn check_result_rejects_unknown_results() {
+        assert!(returned("return 'maybe'").is_err());
+        assert!(returned("return 42").is_err());
+        assert!(returned("return").is_err());
+    }
+
+    #[test]
+    fn check_result_rejects_statuses_only_the_scanner_records() {
+        assert!(returned("return 'Waived', 'accepted'").is_err());
+        assert!(returned("return { status = 'NotApplicable' }").is_err());
+        assert!(returned("return 'not_applicable'").is_err());
+    }
 }
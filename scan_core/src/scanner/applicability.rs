@@ -0,0 +1,151 @@
+use std::cmp::Ordering;
+
+use serde::Deserialize;
+
+use crate::db::models::{OsInfo, Rule};
+
+/// The platforms a rule applies to, as declared in its `applies_to` metadata.
+///
+/// An empty `os_family` list matches every OS. Version bounds are inclusive
+/// and only checked when the device's OS version is known.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Applicability {
+    #[serde(default)]
+    pub os_family: Vec<String>,
+    pub min_version: Option<String>,
+    pub max_version: Option<String>,
+}
+
+impl Applicability {
+    pub fn of(rule: &Rule) -> Self {
+        Self {
+            os_family: rule.os_families.clone(),
+            min_version: rule.min_os_version.clone(),
+            max_version: rule.max_os_version.clone(),
+        }
+    }
+
+    /// Returns why the rule does not apply to a device running `os`, or
+    /// `None` when it does.
+    pub fn mismatch(self: &Self, os: Option<&OsInfo>) -> Option<String> {
+        if self.os_family.is_empty()
+            && self.min_version.is_none()
+            && self.max_version.is_none()
+        {
+            return None;
+        }
+        let Some(os) = os else {
+            return Some("Device OS is unknown".to_string());
+        };
+
+        if !self.os_family.is_empty()
+            && !self
+                .os_family
+                .iter()
+                .any(|family| family.eq_ignore_ascii_case(&os.family))
+        {
+            return Some(format!(
+                "Rule applies to {}, not {}",
+                self.os_family.join(", "),
+                os.family
+            ));
+        }
+
+        let version = os.version.as_deref()?;
+        if let Some(min) = &self.min_version
+            && compare_versions(version, min) == Ordering::Less
+        {
+            return Some(format!(
+                "Rule requires {} {} or later, found {}",
+                os.family, min, version
+            ));
+        }
+        if let Some(max) = &self.max_version
+            && compare_versions(version, max) == Ordering::Greater
+        {
+            return Some(format!(
+                "Rule requires {} {} or earlier, found {}",
+                os.family, max, version
+            ));
+        }
+        None
+    }
+}
+
+/// Compares dotted versions numerically, so that `9.10` comes after `9.2`.
+/// Missing components count as zero and non-numeric ones compare as text.
+fn compare_versions(a: &str, b: &str) -> Ordering {
+    let mut a = a.split(['.', '-', '_']);
+    let mut b = b.split(['.', '-', '_']);
+    loop {
+        let ordering = match (a.next(), b.next()) {
+            (None, None) => return Ordering::Equal,
+            (x, y) => {
+                let (x, y) = (x.unwrap_or("0"), y.unwrap_or("0"));
+                match (x.parse::<u64>(), y.parse::<u64>()) {
+                    (Ok(x), Ok(y)) => x.cmp(&y),
+                    _ => x.cmp(y),
+                }
+            }
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn os(family: &str, version: Option<&str>) -> OsInfo {
+        OsInfo {
+            family: family.to_string(),
+            version: version.map(str::to_string),
+        }
+    }
+
+    fn ubuntu_from(min: &str) -> Applicability {
+        Applicability {
+            os_family: vec!["ubuntu".to_string()],
+            min_version: Some(min.to_string()),
+            max_version: None,
+        }
+    }
+
+    #[test]
+    fn versions_compare_numerically() {
+        assert_eq!(compare_versions("9.10", "9.2"), Ordering::Greater);
+        assert_eq!(compare_versions("22.04", "22.04"), Ordering::Equal);
+        assert_eq!(compare_versions("10", "10.0"), Ordering::Equal);
+        assert_eq!(compare_versions("20.04", "22.04"), Ordering::Less);
+        assert_eq!(compare_versions("1.2-rc1", "1.2-rc2"), Ordering::Less);
+    }
+
+    #[test]
+    fn empty_applicability_matches_any_device() {
+        assert_eq!(Applicability::default().mismatch(None), None);
+    }
+
+    #[test]
+    fn family_and_version_bounds_are_checked() {
+        let rule = ubuntu_from("20.04");
+        assert_eq!(rule.mismatch(Some(&os("ubuntu", Some("22.04")))), None);
+        assert_eq!(rule.mismatch(Some(&os("Ubuntu", Some("20.04")))), None);
+        assert!(rule.mismatch(Some(&os("ubuntu", Some("18.04")))).is_some());
+        assert!(rule.mismatch(Some(&os("netbsd", Some("10.0")))).is_some());
+        assert!(rule.mismatch(None).is_some());
+
+        let rule = Applicability {
+            max_version: Some("22.04".to_string()),
+            ..ubuntu_from("20.04")
+        };
+        assert!(rule.mismatch(Some(&os("ubuntu", Some("24.04")))).is_some());
+    }
+
+    #[test]
+    fn unknown_version_only_checks_family() {
+        let rule = ubuntu_from("20.04");
+        assert_eq!(rule.mismatch(Some(&os("ubuntu", None))), None);
+    }
+}
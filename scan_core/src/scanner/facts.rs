@@ -0,0 +1,194 @@
+use std::collections::BTreeMap;
+
+use crate::db::models::{Facts, OsInfo};
+use crate::scanner::ssh::SSHSession;
+
+/// Package managers in the order they are looked for, with the name each
+/// is reported as.
+const PACKAGE_MANAGERS: &[(&str, &str)] = &[
+    ("apt-get", "apt"),
+    ("dnf", "dnf"),
+    ("yum", "yum"),
+    ("zypper", "zypper"),
+    ("pacman", "pacman"),
+    ("apk", "apk"),
+    ("pkg", "pkg"),
+    ("pkg_add", "pkg_add"),
+    ("pkgin", "pkgin"),
+];
+
+/// Gathers the facts of a device. A fact that cannot be determined is left
+/// empty rather than failing the scan.
+pub async fn gather_facts(session: &SSHSession) -> Facts {
+    let mut facts = Facts::default();
+
+    if let Some(release) = stdout_of(session, "cat /etc/os-release").await {
+        facts.os_release = parse_os_release(&release);
+        facts.os = os_from_release(&facts.os_release);
+    }
+
+    if let Some(uname) = stdout_of(session, "uname -srm").await {
+        let mut fields = uname.split_whitespace();
+        let sysname = fields.next();
+        facts.kernel = fields.next().map(str::to_string);
+        facts.architecture = fields.next().map(str::to_string);
+        // Systems without os-release, such as the BSDs, go by the kernel.
+        if facts.os.is_none() {
+            facts.os = sysname.map(|family| OsInfo {
+                family: family.to_lowercase(),
+                version: facts.kernel.clone(),
+            });
+        }
+    }
+
+    facts.hostname = stdout_of(session, "hostname").await;
+
+    let names: Vec<&str> =
+        PACKAGE_MANAGERS.iter().map(|(bin, _)| *bin).collect();
+    let cmd = format!("command -v {}", names.join(" "));
+    if let Some(found) = stdout_of(session, &cmd).await {
+        facts.package_manager = PACKAGE_MANAGERS
+            .iter()
+            .find(|(bin, _)| {
+                found
+                    .lines()
+                    .any(|path| path.rsplit('/').next() == Some(*bin))
+            })
+            .map(|(_, name)| name.to_string());
+    }
+
+    facts.init_system = stdout_of(session, "ps -o comm= -p 1")
+        .await
+        .map(|comm| comm.rsplit('/').next().unwrap_or_default().to_string());
+
+    let cmd = "ip -o addr show scope global 2>/dev/null || ifconfig -a";
+    if let Some(addresses) = stdout_of(session, cmd).await {
+        facts.ips = parse_addresses(&addresses);
+    }
+
+    facts
+}
+
+/// Returns the trimmed stdout of `cmd`, or `None` if it could not be run or
+/// printed nothing. The exit status is ignored: `command -v` exits non-zero
+/// as soon as one of the names it is given is missing.
+async fn stdout_of(session: &SSHSession, cmd: &str) -> Option<String> {
+    let output = session.exec(cmd).await.ok()?;
+    let stdout = output.stdout.trim();
+    match stdout.is_empty() {
+        true => None,
+        false => Some(stdout.to_string()),
+    }
+}
+
+fn parse_os_release(contents: &str) -> BTreeMap<String, String> {
+    contents
+        .lines()
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| {
+            (
+                key.trim().to_string(),
+                value.trim().trim_matches('"').to_string(),
+            )
+        })
+        .collect()
+}
+
+fn os_from_release(release: &BTreeMap<String, String>) -> Option<OsInfo> {
+    Some(OsInfo {
+        family: release.get("ID")?.to_lowercase(),
+        version: release.get("VERSION_ID").cloned(),
+    })
+}
+
+/// Picks the addresses out of `ip -o addr` or `ifconfig` output, skipping
+/// loopback and link-local ones.
+fn parse_addresses(output: &str) -> Vec<String> {
+    let mut ips = Vec::new();
+    for line in output.lines() {
+        let mut words = line.split_whitespace();
+        while let Some(word) = words.next() {
+            if word != "inet" && word != "inet6" {
+                continue;
+            }
+            let Some(address) = words.next() else {
+                break;
+            };
+            let address = address
+                .split(['/', '%'])
+                .next()
+                .unwrap_or_default()
+                .trim_start_matches("addr:");
+            let local = address.starts_with("127.")
+                || address == "::1"
+                || address.starts_with("fe80:");
+            if !address.is_empty()
+                && !local
+                && !ips.iter().any(|ip| ip == address)
+            {
+                ips.push(address.to_string());
+            }
+        }
+    }
+    ips
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn os_release_values_may_be_quoted() {
+        let release = parse_os_release(
+            "NAME=\"Ubuntu\"\nID=ubuntu\nVERSION_ID=\"22.04\"\n\
+             PRETTY_NAME=\"Ubuntu 22.04.4 LTS\"\n# comment\n",
+        );
+        assert_eq!(release["NAME"], "Ubuntu");
+        assert_eq!(release["PRETTY_NAME"], "Ubuntu 22.04.4 LTS");
+        assert_eq!(release.len(), 4);
+
+        let os = os_from_release(&release).unwrap();
+        assert_eq!(os.family, "ubuntu");
+        assert_eq!(os.version.as_deref(), Some("22.04"));
+    }
+
+    #[test]
+    fn os_release_without_id_has_no_os() {
+        let release = parse_os_release("NAME=Linux\nVERSION_ID=1\n");
+        assert!(os_from_release(&release).is_none());
+    }
+
+    #[test]
+    fn addresses_are_read_from_ip_output() {
+        let output = "\
+1: lo    inet 127.0.0.1/8 scope host lo\\       valid_lft forever
+2: eth0    inet 10.0.0.5/24 brd 10.0.0.255 scope global eth0\\       valid_lft forever
+2: eth0    inet6 2001:db8::5/64 scope global \\       valid_lft forever
+2: eth0    inet6 fe80::1/64 scope link \\       valid_lft forever
+3: eth1    inet 10.0.0.5/24 scope global secondary eth1";
+        assert_eq!(parse_addresses(output), ["10.0.0.5", "2001:db8::5"]);
+    }
+
+    #[test]
+    fn addresses_are_read_from_bsd_ifconfig_output() {
+        let output = "\
+em0: flags=8843<UP,BROADCAST,RUNNING,SIMPLEX,MULTICAST> mtu 1500
+\tinet 192.168.1.20 netmask 0xffffff00 broadcast 192.168.1.255
+\tinet6 fe80::a00:27ff:fe12:3456%em0 prefixlen 64 scopeid 0x1
+\tinet6 2001:db8::20 prefixlen 64
+lo0: flags=8049<UP,LOOPBACK,RUNNING,MULTICAST> mtu 16384
+\tinet 127.0.0.1 netmask 0xff000000
+\tinet6 ::1 prefixlen 128";
+        assert_eq!(parse_addresses(output), ["192.168.1.20", "2001:db8::20"]);
+    }
+
+    #[test]
+    fn addresses_are_read_from_net_tools_ifconfig_output() {
+        let output = "\
+eth0      Link encap:Ethernet  HWaddr 08:00:27:12:34:56
+          inet addr:10.0.2.15  Bcast:10.0.2.255  Mask:255.255.255.0
+lo        Link encap:Local Loopback
+          inet addr:127.0.0.1  Mask:255.0.0.0";
+        assert_eq!(parse_addresses(output), ["10.0.2.15"]);
+    }
+}
@@ -0,0 +1,111 @@
+use std::fs;
+
+use anyhow::{Context, Result, bail};
+use async_trait::async_trait;
+
+use crate::db::Db;
+use crate::db::models::{HostKey, HostKeyStatus, Target};
+use crate::scanner::ssh::parse_known_hosts;
+
+/// Decides whether the host key a device presents can be trusted.
+#[async_trait]
+pub trait HostKeyStore: Send + Sync {
+    async fn verify(
+        self: &Self,
+        target: &Target,
+        presented: &HostKey,
+    ) -> Result<()>;
+}
+
+/// Checks the key presented by a device against its pinned host keys.
+///
+/// A device with no usable pinned keys has the presented key recorded as
+/// pending (trust on first use), but is not scanned until it is approved.
+#[async_trait]
+impl HostKeyStore for Db {
+    async fn verify(
+        self: &Self,
+        target: &Target,
+        presented: &HostKey,
+    ) -> Result<()> {
+        let pinned = self.get_host_keys_for_device(target.device_id).await?;
+
+        if let Some(key) =
+            pinned.iter().find(|k| k.public_key == presented.public_key)
+        {
+            match key.status {
+                HostKeyStatus::Trusted => return Ok(()),
+                HostKeyStatus::Pending => bail!(
+                    "Host key {} for '{}' is awaiting approval",
+                    presented.fingerprint,
+                    target.address
+                ),
+                HostKeyStatus::Revoked => bail!(
+                    "Host key mismatch for '{}': key {} has been revoked",
+                    target.address,
+                    presented.fingerprint
+                ),
+            }
+        }
+
+        if pinned.iter().all(|k| k.status == HostKeyStatus::Revoked) {
+            self.add_host_key(
+                target.device_id,
+                presented,
+                HostKeyStatus::Pending,
+            )
+            .await?;
+            bail!(
+                "Host key {} for '{}' recorded on first use, awaiting approval",
+                presented.fingerprint,
+                target.address
+            );
+        }
+
+        bail!(
+            "Host key mismatch for '{}': presented key {} is not pinned",
+            target.address,
+            presented.fingerprint
+        )
+    }
+}
+
+/// Host keys read from an OpenSSH known_hosts file.
+pub struct KnownHosts {
+    contents: String,
+}
+
+impl KnownHosts {
+    pub fn from_file(path: &str) -> Result<Self> {
+        let contents = fs::read_to_string(path)
+            .context(format!("Failed to read known_hosts file '{}'", path))?;
+        Ok(Self { contents })
+    }
+}
+
+#[async_trait]
+impl HostKeyStore for KnownHosts {
+    async fn verify(
+        self: &Self,
+        target: &Target,
+        presented: &HostKey,
+    ) -> Result<()> {
+        let known =
+            parse_known_hosts(&self.contents, &target.address, target.port);
+        if known.iter().any(|k| k.public_key == presented.public_key) {
+            return Ok(());
+        }
+        if known.is_empty() {
+            bail!(
+                "Host key {} for '{}' is not in known_hosts",
+                presented.fingerprint,
+                target.address
+            );
+        }
+        bail!(
+            "Host key mismatch for '{}': presented key {} is not in known_hosts",
+            target.address,
+            presented.fingerprint
+        )
+    }
+}
@@ -0,0 +1,196 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::Result;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::{Instant, sleep_until};
+
+use crate::db::models::Target;
+
+/// Caps on how much load a scan puts on the network and on each device.
+#[derive(Debug, Clone)]
+pub struct ConcurrencyLimits {
+    /// Devices scanned at the same time.
+    pub max_devices: usize,
+    /// Devices scanned at the same time within one subnet.
+    pub per_subnet: Option<usize>,
+    /// Prefix length grouping IPv4 addresses into subnets. IPv6 addresses
+    /// are grouped by /64, and host names are not grouped at all.
+    pub subnet_prefix: u8,
+    /// Devices scanned at the same time within a device group, by name.
+    pub per_group: HashMap<String, usize>,
+    /// Rules run at the same time on one device, each over channels of
+    /// the device's one connection.
+    pub rules_per_device: usize,
+    /// Commands running at the same time on one device.
+    pub commands_per_device: usize,
+    /// New SSH connections opened per second, across all devices.
+    pub connects_per_second: Option<u32>,
+}
+
+impl Default for ConcurrencyLimits {
+    fn default() -> Self {
+        Self {
+            max_devices: 64,
+            per_subnet: None,
+            subnet_prefix: 24,
+            per_group: HashMap::new(),
+            rules_per_device: 1,
+            commands_per_device: 4,
+            connects_per_second: None,
+        }
+    }
+}
+
+/// Hands out the permits a device's scan needs before it may start.
+pub(crate) struct Scheduler {
+    limits: ConcurrencyLimits,
+    global: Arc<Semaphore>,
+    subnets: Mutex<HashMap<String, Arc<Semaphore>>>,
+    groups: HashMap<String, Arc<Semaphore>>,
+    next_connect: tokio::sync::Mutex<Instant>,
+}
+
+impl Scheduler {
+    pub(crate) fn new(limits: ConcurrencyLimits) -> Self {
+        let groups = limits
+            .per_group
+            .iter()
+            .map(|(name, n)| {
+                (name.clone(), Arc::new(Semaphore::new((*n).max(1))))
+            })
+            .collect();
+        Self {
+            global: Arc::new(Semaphore::new(limits.max_devices.max(1))),
+            subnets: Mutex::new(HashMap::new()),
+            groups,
+            next_connect: tokio::sync::Mutex::new(Instant::now()),
+            limits,
+        }
+    }
+
+    /// Waits until `target` may be scanned. The scan may run for as long as
+    /// the returned permits are held.
+    ///
+    /// Subnet and group permits are taken in a fixed order, and the global
+    /// one last, so that tasks waiting on each other cannot deadlock.
+    pub(crate) async fn acquire(
+        self: &Self,
+        target: &Target,
+    ) -> Result<Vec<OwnedSemaphorePermit>> {
+        let mut semaphores = Vec::new();
+        if let Some(limit) = self.limits.per_subnet
+            && let Some(subnet) =
+                subnet_of(&target.address, self.limits.subnet_prefix)
+        {
+            let mut subnets = self.subnets.lock().unwrap();
+            let semaphore = subnets
+                .entry(subnet)
+                .or_insert_with(|| Arc::new(Semaphore::new(limit.max(1))));
+            semaphores.push(semaphore.clone());
+        }
+        let mut groups: Vec<&String> = target
+            .groups
+            .iter()
+            .filter(|g| self.groups.contains_key(*g))
+            .collect();
+        groups.sort();
+        groups.dedup();
+        semaphores.extend(groups.into_iter().map(|g| self.groups[g].clone()));
+        semaphores.push(self.global.clone());
+
+        let mut permits = Vec::new();
+        for semaphore in semaphores {
+            permits.push(semaphore.acquire_owned().await?);
+        }
+        Ok(permits)
+    }
+
+    /// Spaces out new connections to honour `connects_per_second`.
+    pub(crate) async fn throttle_connect(self: &Self) {
+        let Some(rate) = self.limits.connects_per_second else {
+            return;
+        };
+        let interval = Duration::from_secs(1) / rate.max(1);
+        let slot = {
+            let mut next = self.next_connect.lock().await;
+            let slot = (*next).max(Instant::now());
+            *next = slot + interval;
+            slot
+        };
+        sleep_until(slot).await;
+    }
+}
+
+/// Returns the subnet an IP address belongs to, such as `10.0.3.0/24`.
+fn subnet_of(address: &str, prefix: u8) -> Option<String> {
+    match address.parse::<IpAddr>().ok()? {
+        IpAddr::V4(ip) => {
+            let prefix = prefix.min(32);
+            let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+            let network = Ipv4Addr::from(u32::from(ip) & mask);
+            Some(format!("{}/{}", network, prefix))
+        }
+        IpAddr::V6(ip) => {
+            let mask = u128::MAX << 64;
+            let network = Ipv6Addr::from(u128::from(ip) & mask);
+            Some(format!("{}/64", network))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::models::Credential;
+
+    fn target(address: &str, groups: &[&str]) -> Target {
+        Target {
+            device_id: 1,
+            address: address.to_string(),
+            port: 22,
+            username: "audit".to_string(),
+            credential: Credential::Agent,
+            os_family: None,
+            os_version: None,
+            os_override: false,
+            params: HashMap::new(),
+            waivers: HashMap::new(),
+            groups: groups.iter().map(|g| g.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn subnets_group_addresses_by_prefix() {
+        assert_eq!(subnet_of("10.0.3.17", 24).as_deref(), Some("10.0.3.0/24"));
+        assert_eq!(subnet_of("10.0.3.17", 16).as_deref(), Some("10.0.0.0/16"));
+        assert_eq!(subnet_of("10.0.3.17", 0).as_deref(), Some("0.0.0.0/0"));
+        assert_eq!(subnet_of("10.0.3.17", 40).as_deref(), Some("10.0.3.17/32"));
+        assert_eq!(
+            subnet_of("2001:db8::1", 24).as_deref(),
+            Some("2001:db8::/64")
+        );
+        assert_eq!(subnet_of("web-1.example.com", 24), None);
+    }
+
+    #[tokio::test]
+    async fn a_zero_group_limit_still_lets_devices_through() {
+        let limits = ConcurrencyLimits {
+            per_group: HashMap::from([("dmz".to_string(), 0)]),
+            per_subnet: Some(0),
+            ..ConcurrencyLimits::default()
+        };
+        let scheduler = Scheduler::new(limits);
+        let permits = tokio::time::timeout(
+            Duration::from_secs(1),
+            scheduler.acquire(&target("10.0.0.5", &["dmz"])),
+        )
+        .await
+        .expect("acquire must not wait forever")
+        .unwrap();
+        // Subnet, group and global permits.
+        assert_eq!(permits.len(), 3);
+    }
+}
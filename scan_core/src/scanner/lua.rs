@@ -1,9 +1,138 @@
-use anyhow::Result;
-use mlua::{UserData, UserDataMethods, prelude::*};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use anyhow::{Context, Result};
+use mlua::prelude::{
+    Lua, LuaError, LuaFunction, LuaMultiValue, LuaResult, LuaSerdeExt,
+    LuaTable, LuaValue,
+};
+use mlua::{
+    ChunkMode, DeserializeOptions, HookTriggers, LuaOptions, StdLib, UserData,
+    UserDataMethods, VmState,
+};
 use regex::Regex;
+use serde::Deserialize;
+use sqlx::types::Json;
+
+use crate::db::models::{CheckType, ParamSpecs, Rule, SeverityLevel};
+use crate::scanner::applicability::Applicability;
+use crate::scanner::params::validate_specs;
+
+const REGISTERED_CHECKS: &str = "registered_checks";
+
+/// How many instructions run between two checks of the instruction limit.
+const HOOK_INTERVAL: u32 = 10_000;
+
+/// Rule scripts come from a shared database, so they get neither `os`, `io`,
+/// `debug` nor `package`, and can only load source text, never bytecode.
+const SANDBOX: &str = r#"
+local load = load
+-- Passing `env` on only when given: an explicit nil would become _ENV.
+_G.load = function(chunk, chunkname, _, ...)
+    return load(chunk, chunkname, "t", ...)
+end
+dofile = nil
+loadfile = nil
+string.dump = nil
+"#;
+
+/// Resource limits for the Lua state a rule runs in.
+#[derive(Debug, Clone)]
+pub struct LuaLimits {
+    /// Bytes of memory the state may allocate.
+    pub memory: usize,
+    /// Instructions a rule may execute before it is aborted.
+    pub instructions: u64,
+}
+
+impl Default for LuaLimits {
+    fn default() -> Self {
+        Self {
+            memory: 64 * 1024 * 1024,
+            instructions: 100_000_000,
+        }
+    }
+}
+
+/// Metadata a rule declares, either as `METADATA` or via `register_check`.
+#[derive(Debug, Deserialize)]
+pub struct RuleMetadata {
+    pub id: String,
+    pub name: String,
+    pub description: Option<String>,
+    pub severity: SeverityLevel,
+    #[serde(default)]
+    pub applies_to: Applicability,
+    /// Tunable parameters, exposed to the rule as the `params` global.
+    #[serde(default)]
+    pub params: ParamSpecs,
+}
+
+/// A rule defined by a script, together with the function that runs it.
+pub struct LoadedCheck {
+    pub metadata: RuleMetadata,
+    pub run: LuaFunction,
+    /// Optional `applies(conn)` predicate deciding whether to run the rule.
+    pub applies: Option<LuaFunction>,
+}
+
+pub fn init_lua(limits: &LuaLimits) -> Result<Lua> {
+    let lua = Lua::new_with(
+        StdLib::COROUTINE
+            | StdLib::TABLE
+            | StdLib::STRING
+            | StdLib::UTF8
+            | StdLib::MATH,
+        LuaOptions::default(),
+    )
+    .context("Could not create Lua state")?;
+    lua.load(SANDBOX)
+        .set_name("sandbox")
+        .exec()
+        .context("Could not restrict Lua globals")?;
+
+    lua.set_memory_limit(limits.memory)
+        .context("Could not set Lua memory limit")?;
+    let max_instructions = limits.instructions;
+    let executed = AtomicU64::new(0);
+    // Rules run in coroutines made by `call_async`. A global hook is
+    // inherited by every thread mlua creates after it is set, and Lua copies
+    // it into coroutines the rule creates itself; `set_hook` would only
+    // cover the main thread.
+    lua.set_global_hook(
+        HookTriggers::new().every_nth_instruction(HOOK_INTERVAL),
+        move |_, _| {
+            let count = executed
+                .fetch_add(HOOK_INTERVAL as u64, Ordering::Relaxed)
+                + HOOK_INTERVAL as u64;
+            if count > max_instructions {
+                return Err(LuaError::runtime(format!(
+                    "Instruction limit of {} exceeded",
+                    max_instructions
+                )));
+            }
+            Ok(VmState::Continue)
+        },
+    )
+    .context("Could not set Lua instruction limit")?;
 
-pub fn init_lua() -> Result<Lua> {
-    let lua = Lua::new();
+    let registry = lua.create_table()?;
+    lua.set_named_registry_value(REGISTERED_CHECKS, registry)?;
+    let register_fn = lua
+        .create_function(|lua, check: LuaTable| {
+            check.get::<String>("id").map_err(|_| {
+                LuaError::runtime("register_check requires a string 'id'")
+            })?;
+            check.get::<LuaFunction>("run").map_err(|_| {
+                LuaError::runtime("register_check requires a 'run' function")
+            })?;
+            let registry: LuaTable =
+                lua.named_registry_value(REGISTERED_CHECKS)?;
+            registry.push(check)
+        })
+        .context("Could not create 'register_check' function")?;
+    lua.globals()
+        .set("register_check", register_fn)
+        .context("Could not set 'register_check' global")?;
 
     let compile_fn = lua
         .create_function(|_, pattern: String| match Regex::new(&pattern) {
@@ -24,6 +153,131 @@ pub fn init_lua() -> Result<Lua> {
     Ok(lua)
 }
 
+/// Wraps `value` so that neither it nor any table nested in it can be
+/// modified from Lua. Reads, `pairs`, `ipairs` and `#` work as usual.
+pub fn read_only(lua: &Lua, value: LuaValue) -> LuaResult<LuaValue> {
+    let LuaValue::Table(table) = value else {
+        return Ok(value);
+    };
+    let inner = lua.create_table()?;
+    for pair in table.pairs::<LuaValue, LuaValue>() {
+        let (key, value) = pair?;
+        inner.raw_set(key, read_only(lua, value)?)?;
+    }
+
+    let next: LuaFunction = lua.globals().get("next")?;
+    let meta = lua.create_table()?;
+    meta.set("__index", inner.clone())?;
+    meta.set(
+        "__newindex",
+        lua.create_function(|_, _: LuaMultiValue| -> LuaResult<()> {
+            Err(LuaError::runtime("attempt to modify a read-only table"))
+        })?,
+    )?;
+    let len_of = inner.clone();
+    meta.set(
+        "__len",
+        lua.create_function(move |_, _: LuaValue| Ok(len_of.raw_len()))?,
+    )?;
+    meta.set(
+        "__pairs",
+        lua.create_function(move |_, _: LuaValue| {
+            Ok((next.clone(), inner.clone(), LuaValue::Nil))
+        })?,
+    )?;
+    meta.set("__metatable", false)?;
+
+    let proxy = lua.create_table()?;
+    proxy.set_metatable(Some(meta))?;
+    Ok(LuaValue::Table(proxy))
+}
+
+/// Executes a rule script and returns every check it defines.
+///
+/// Scripts either call `register_check` once per rule, or define a single
+/// rule through the `METADATA` table and a global `run_check` function.
+/// Either way a rule may add an `applies(conn)` predicate, as an `applies`
+/// field or global.
+pub fn load_checks(
+    lua: &Lua,
+    source: &str,
+    name: &str,
+) -> Result<Vec<LoadedCheck>> {
+    lua.load(source)
+        .set_name(name)
+        .set_mode(ChunkMode::Text)
+        .exec()
+        .context(format!("Failed to load '{}'", name))?;
+
+    let options = DeserializeOptions::new().deny_unsupported_types(false);
+    let registry: LuaTable = lua.named_registry_value(REGISTERED_CHECKS)?;
+    let mut checks = Vec::new();
+    for check in registry.sequence_values::<LuaTable>() {
+        let check = check?;
+        let metadata: RuleMetadata = lua
+            .from_value_with(LuaValue::Table(check.clone()), options)
+            .context(format!("Invalid check registered in '{}'", name))?;
+        let run: LuaFunction = check.get("run")?;
+        let applies: Option<LuaFunction> = check.get("applies")?;
+        checks.push(LoadedCheck {
+            metadata,
+            run,
+            applies,
+        });
+    }
+
+    if checks.is_empty() {
+        let meta_table: LuaTable = lua
+            .globals()
+            .get("METADATA")
+            .context(format!("'{}' does not define any checks", name))?;
+        let metadata: RuleMetadata = lua
+            .from_value_with(LuaValue::Table(meta_table), options)
+            .context(format!("Invalid METADATA in '{}'", name))?;
+        let run: LuaFunction = lua
+            .globals()
+            .get("run_check")
+            .context(format!("'{}' does not define run_check", name))?;
+        let applies: Option<LuaFunction> = lua.globals().get("applies")?;
+        checks.push(LoadedCheck {
+            metadata,
+            run,
+            applies,
+        });
+    }
+
+    Ok(checks)
+}
+
+/// Loads a rule script into a fresh Lua state and returns the rules it
+/// defines, each carrying the full script as its body.
+pub fn rules_from_script(source: &str, name: &str) -> Result<Vec<Rule>> {
+    let lua = init_lua(&LuaLimits::default())?;
+    load_checks(&lua, source, name)?
+        .into_iter()
+        .map(|check| {
+            let metadata = check.metadata;
+            validate_specs(&metadata.params).context(format!(
+                "Invalid parameters for rule '{}'",
+                metadata.id
+            ))?;
+            Ok(Rule {
+                id: metadata.id,
+                name: metadata.name,
+                description: metadata.description,
+                severity: metadata.severity,
+                check_type: CheckType::Lua,
+                script_body: source.to_string(),
+                os_families: metadata.applies_to.os_family,
+                min_os_version: metadata.applies_to.min_version,
+                max_os_version: metadata.applies_to.max_version,
+                params: Json(metadata.params),
+                revision_id: None,
+            })
+        })
+        .collect()
+}
+
 pub struct LuaRegex(pub Regex);
 
 impl UserData for LuaRegex {
@@ -60,3 +314,152 @@ impl UserData for LuaRegex {
         });
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits() -> LuaLimits {
+        LuaLimits {
+            memory: 4 * 1024 * 1024,
+            instructions: 1_000_000,
+        }
+    }
+
+    /// Runs `body` the way rules are run: as a function called through
+    /// `call_async`, which executes it in a coroutine.
+    async fn run(body: &str) -> LuaResult<LuaValue> {
+        let lua = init_lua(&limits()).unwrap();
+        let f: LuaFunction = lua
+            .load(format!("return function() {} end", body))
+            .eval()
+            .unwrap();
+        f.call_async(()).await
+    }
+
+    #[tokio::test]
+    async fn unsafe_libraries_are_unavailable() {
+        let hidden = run("return os == nil and io == nil and debug == nil \
+            and require == nil and package == nil and dofile == nil \
+            and loadfile == nil")
+        .await
+        .unwrap();
+        assert_eq!(hidden, LuaValue::Boolean(true));
+    }
+
+    #[tokio::test]
+    async fn bytecode_cannot_be_loaded() {
+        let dumped = run(
+            "return pcall(function() load(string.dump(function() end)) end)",
+        )
+        .await
+        .unwrap();
+        assert_eq!(dumped, LuaValue::Boolean(false));
+
+        let lua = init_lua(&limits()).unwrap();
+        let (chunk, err): (LuaValue, String) = lua
+            .load(r#"return load("\27Lua", "chunk", "b")"#)
+            .eval()
+            .unwrap();
+        assert_eq!(chunk, LuaValue::Nil);
+        assert!(err.contains("binary chunk"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn load_keeps_its_env_argument() {
+        let value = run(r#"return load("return x", "c", "t", { x = 7 })()"#)
+            .await
+            .unwrap();
+        assert_eq!(value, LuaValue::Integer(7));
+    }
+
+    #[tokio::test]
+    async fn endless_loops_hit_the_instruction_limit() {
+        let err = run("while true do end").await.unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("Instruction limit of 1000000 exceeded"),
+            "{}",
+            err
+        );
+
+        // Coroutines the rule creates itself are limited too.
+        let err = run("coroutine.wrap(function() while true do end end)()")
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("Instruction limit"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn allocations_hit_the_memory_limit() {
+        let err = run(
+            "local t = {} for i = 1, 1e7 do t[i] = string.rep('x', 1024) .. i end",
+        )
+        .await
+        .unwrap_err();
+        assert!(err.to_string().contains("not enough memory"), "{}", err);
+    }
+
+    const REGISTERED: &str = r#"
+register_check({
+    id = "R-1",
+    name = "First",
+    severity = "Low",
+    applies = function(conn) return true end,
+    run = function(conn) return true end,
+})
+register_check({
+    id = "R-2",
+    name = "Second",
+    severity = "High",
+    run = function(conn) return false, "nope" end,
+})
+"#;
+
+    #[test]
+    fn register_check_defines_several_rules() {
+        let lua = init_lua(&limits()).unwrap();
+        let checks = load_checks(&lua, REGISTERED, "registered.lua").unwrap();
+        let ids: Vec<&str> = checks
+            .iter()
+            .map(|check| check.metadata.id.as_str())
+            .collect();
+        assert_eq!(ids, ["R-1", "R-2"]);
+        assert!(checks[0].applies.is_some());
+        assert!(checks[1].applies.is_none());
+
+        let rules = rules_from_script(REGISTERED, "registered.lua").unwrap();
+        assert_eq!(rules[1].severity, SeverityLevel::High);
+        assert!(rules.iter().all(|rule| rule.script_body == REGISTERED));
+    }
+
+    #[test]
+    fn register_check_requires_id_and_run() {
+        let no_id = r#"register_check({ name = "x", severity = "Low",
+            run = function() end })"#;
+        let err = rules_from_script(no_id, "no_id.lua").unwrap_err();
+        assert!(format!("{:#}", err).contains("requires a string 'id'"));
+
+        let no_run =
+            r#"register_check({ id = "R-1", name = "x", severity = "Low" })"#;
+        let err = rules_from_script(no_run, "no_run.lua").unwrap_err();
+        assert!(format!("{:#}", err).contains("requires a 'run' function"));
+    }
+
+    #[test]
+    fn metadata_is_used_when_nothing_is_registered() {
+        let script = r#"
+METADATA = { id = "M-1", name = "Meta", severity = "Medium" }
+function applies(conn) return false end
+function run_check(conn) return true end
+"#;
+        let lua = init_lua(&limits()).unwrap();
+        let checks = load_checks(&lua, script, "meta.lua").unwrap();
+        assert_eq!(checks.len(), 1);
+        assert_eq!(checks[0].metadata.id, "M-1");
+        assert!(checks[0].applies.is_some());
+
+        let err = rules_from_script("local x = 1", "empty.lua").unwrap_err();
+        assert!(format!("{:#}", err).contains("does not define any checks"));
+    }
+}
@@ -0,0 +1,138 @@
+use anyhow::{Context, Result, bail};
+use serde_json::Value;
+
+use crate::db::models::{ParamSpec, ParamSpecs, ParamType, ParamValues};
+
+impl ParamType {
+    pub fn accepts(self: &Self, value: &Value) -> bool {
+        match self {
+            ParamType::String => value.is_string(),
+            ParamType::Number => value.is_number(),
+            ParamType::Integer => value.is_i64() || value.is_u64(),
+            ParamType::Boolean => value.is_boolean(),
+            ParamType::List => value.is_array(),
+        }
+    }
+}
+
+fn check_value(name: &str, spec: &ParamSpec, value: &Value) -> Result<()> {
+    if !spec.kind.accepts(value) {
+        bail!(
+            "Parameter '{}' expects a {:?} value, got {}",
+            name,
+            spec.kind,
+            value
+        );
+    }
+    Ok(())
+}
+
+/// Checks that every declared default matches its parameter's type.
+pub fn validate_specs(specs: &ParamSpecs) -> Result<()> {
+    for (name, spec) in specs {
+        check_value(name, spec, &spec.default)?;
+    }
+    Ok(())
+}
+
+/// Replaces the defaults of `specs` with `overrides`, as a profile does.
+pub fn override_defaults(
+    specs: &mut ParamSpecs,
+    overrides: &ParamValues,
+) -> Result<()> {
+    for (name, value) in overrides {
+        let spec = specs
+            .get_mut(name)
+            .context(format!("Unknown parameter '{}'", name))?;
+        check_value(name, spec, value)?;
+        spec.default = value.clone();
+    }
+    Ok(())
+}
+
+/// Returns the values a rule runs with: its declared defaults, overridden by
+/// those given for the device.
+pub fn resolve_params(
+    specs: &ParamSpecs,
+    overrides: Option<&ParamValues>,
+) -> Result<ParamValues> {
+    let mut values: ParamValues = specs
+        .iter()
+        .map(|(name, spec)| (name.clone(), spec.default.clone()))
+        .collect();
+    for (name, value) in overrides.into_iter().flatten() {
+        let spec = specs
+            .get(name)
+            .context(format!("Unknown parameter '{}'", name))?;
+        check_value(name, spec, value)?;
+        values.insert(name.clone(), value.clone());
+    }
+    Ok(values)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn specs() -> ParamSpecs {
+        ParamSpecs::from([
+            (
+                "firewalls".to_string(),
+                ParamSpec {
+                    kind: ParamType::List,
+                    default: json!(["ufw", "nftables"]),
+                    description: None,
+                },
+            ),
+            (
+                "max_age".to_string(),
+                ParamSpec {
+                    kind: ParamType::Integer,
+                    default: json!(90),
+                    description: None,
+                },
+            ),
+        ])
+    }
+
+    #[test]
+    fn defaults_must_match_their_type() {
+        assert!(validate_specs(&specs()).is_ok());
+        let mut specs = specs();
+        specs.get_mut("max_age").unwrap().default = json!("ninety");
+        assert!(validate_specs(&specs).is_err());
+    }
+
+    #[test]
+    fn device_values_override_defaults() {
+        let values = resolve_params(&specs(), None).unwrap();
+        assert_eq!(values["max_age"], json!(90));
+
+        let overrides = ParamValues::from([("max_age".to_string(), json!(30))]);
+        let values = resolve_params(&specs(), Some(&overrides)).unwrap();
+        assert_eq!(values["max_age"], json!(30));
+        assert_eq!(values["firewalls"], json!(["ufw", "nftables"]));
+    }
+
+    #[test]
+    fn overrides_must_be_declared_and_typed() {
+        let unknown = ParamValues::from([("colour".to_string(), json!("red"))]);
+        assert!(resolve_params(&specs(), Some(&unknown)).is_err());
+
+        let mistyped = ParamValues::from([("max_age".to_string(), json!(1.5))]);
+        assert!(resolve_params(&specs(), Some(&mistyped)).is_err());
+        let mut specs = specs();
+        assert!(override_defaults(&mut specs, &mistyped).is_err());
+    }
+
+    #[test]
+    fn profile_overrides_replace_defaults() {
+        let mut specs = specs();
+        let overrides =
+            ParamValues::from([("firewalls".to_string(), json!(["pf"]))]);
+        override_defaults(&mut specs, &overrides).unwrap();
+        assert_eq!(specs["firewalls"].default, json!(["pf"]));
+    }
+}
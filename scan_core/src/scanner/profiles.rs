@@ -0,0 +1,212 @@
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::{Context, Result, bail};
+use async_trait::async_trait;
+
+use crate::db::Db;
+use crate::db::models::{Profile, ProfileRule, Rule};
+use crate::scanner::params::override_defaults;
+
+/// Resolves compliance profiles to the rules they run.
+#[async_trait]
+pub trait RuleSource: Send + Sync {
+    /// Returns the rules of the named profile, with its severity overrides
+    /// applied.
+    async fn profile_rules(self: &Self, name: &str) -> Result<Vec<Rule>>;
+}
+
+/// Profiles inherit the rules of their parent; each profile down the chain
+/// can add rules, override their severity and parameter defaults, or exclude
+/// inherited ones.
+#[async_trait]
+impl RuleSource for Db {
+    async fn profile_rules(self: &Self, name: &str) -> Result<Vec<Rule>> {
+        let profile = self
+            .get_profile_by_name(name.to_string())
+            .await?
+            .context(format!("No profile named '{}'", name))?;
+
+        let mut chain = vec![profile];
+        while let Some(parent_id) = chain.last().and_then(|p| p.parent_id) {
+            if chain.iter().any(|p| p.id == parent_id) {
+                bail!("Profile '{}' inherits from itself", name);
+            }
+            let parent = self
+                .get_profile(parent_id)
+                .await?
+                .context(format!("No profile with id {}", parent_id))?;
+            chain.push(parent);
+        }
+
+        let catalogue: HashMap<String, Rule> = self
+            .get_all_rules()
+            .await?
+            .into_iter()
+            .map(|rule| (rule.id.clone(), rule))
+            .collect();
+        let mut entries = Vec::new();
+        for profile in chain.into_iter().rev() {
+            let rules = self.get_profile_rules(profile.id).await?;
+            entries.push((profile, rules));
+        }
+        resolve_profile(&entries, &catalogue)
+    }
+}
+
+/// Applies the entries of each profile, root first, to the rule catalogue.
+/// A rule excluded higher up the chain can be included again further down,
+/// starting over from its catalogue definition.
+fn resolve_profile(
+    chain: &[(Profile, Vec<ProfileRule>)],
+    catalogue: &HashMap<String, Rule>,
+) -> Result<Vec<Rule>> {
+    let mut selected = BTreeMap::new();
+    for (profile, entries) in chain {
+        for entry in entries {
+            if entry.excluded {
+                selected.remove(&entry.rule_id);
+                continue;
+            }
+            let mut rule = match selected.remove(&entry.rule_id) {
+                Some(rule) => rule,
+                None => {
+                    catalogue.get(&entry.rule_id).cloned().context(format!(
+                        "Profile '{}' refers to missing rule '{}'",
+                        profile.name, entry.rule_id
+                    ))?
+                }
+            };
+            if let Some(severity) = &entry.severity {
+                rule.severity = severity.clone();
+            }
+            override_defaults(&mut rule.params, &entry.params).context(
+                format!(
+                    "Invalid parameters for '{}' in profile '{}'",
+                    entry.rule_id, profile.name
+                ),
+            )?;
+            selected.insert(entry.rule_id.clone(), rule);
+        }
+    }
+    Ok(selected.into_values().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use sqlx::types::Json;
+
+    use super::*;
+    use crate::db::models::{
+        CheckType, ParamSpec, ParamSpecs, ParamType, ParamValues, SeverityLevel,
+    };
+
+    fn rule(id: &str) -> Rule {
+        Rule {
+            id: id.to_string(),
+            name: id.to_string(),
+            description: None,
+            severity: SeverityLevel::Medium,
+            check_type: CheckType::Lua,
+            script_body: String::new(),
+            os_families: Vec::new(),
+            min_os_version: None,
+            max_os_version: None,
+            params: Json(ParamSpecs::from([(
+                "max_age".to_string(),
+                ParamSpec {
+                    kind: ParamType::Integer,
+                    default: json!(90),
+                    description: None,
+                },
+            )])),
+            revision_id: None,
+        }
+    }
+
+    fn catalogue() -> HashMap<String, Rule> {
+        ["A", "B", "C"]
+            .into_iter()
+            .map(|id| (id.to_string(), rule(id)))
+            .collect()
+    }
+
+    fn profile(id: i64, parent_id: Option<i64>) -> Profile {
+        Profile {
+            id,
+            name: format!("profile-{}", id),
+            description: None,
+            parent_id,
+        }
+    }
+
+    fn entry(profile_id: i64, rule_id: &str) -> ProfileRule {
+        ProfileRule {
+            profile_id,
+            rule_id: rule_id.to_string(),
+            severity: None,
+            excluded: false,
+            params: Json(ParamValues::new()),
+        }
+    }
+
+    fn ids(rules: &[Rule]) -> Vec<&str> {
+        rules.iter().map(|rule| rule.id.as_str()).collect()
+    }
+
+    #[test]
+    fn children_inherit_and_add_rules() {
+        let chain = vec![
+            (profile(1, None), vec![entry(1, "A"), entry(1, "B")]),
+            (profile(2, Some(1)), vec![entry(2, "C")]),
+        ];
+        let rules = resolve_profile(&chain, &catalogue()).unwrap();
+        assert_eq!(ids(&rules), ["A", "B", "C"]);
+    }
+
+    #[test]
+    fn children_override_severity_and_params() {
+        let mut stricter = entry(2, "A");
+        stricter.severity = Some(SeverityLevel::Critical);
+        stricter.params =
+            Json(ParamValues::from([("max_age".to_string(), json!(30))]));
+        let chain = vec![
+            (profile(1, None), vec![entry(1, "A")]),
+            (profile(2, Some(1)), vec![stricter]),
+        ];
+        let rules = resolve_profile(&chain, &catalogue()).unwrap();
+        assert_eq!(rules[0].severity, SeverityLevel::Critical);
+        assert_eq!(rules[0].params["max_age"].default, json!(30));
+    }
+
+    #[test]
+    fn excluded_rules_can_be_included_again() {
+        let mut modified = entry(1, "A");
+        modified.severity = Some(SeverityLevel::Low);
+        let mut excluded = entry(2, "A");
+        excluded.excluded = true;
+        let mut chain = vec![
+            (profile(1, None), vec![modified, entry(1, "B")]),
+            (profile(2, Some(1)), vec![excluded]),
+        ];
+        let rules = resolve_profile(&chain, &catalogue()).unwrap();
+        assert_eq!(ids(&rules), ["B"]);
+
+        chain.push((profile(3, Some(2)), vec![entry(3, "A")]));
+        let rules = resolve_profile(&chain, &catalogue()).unwrap();
+        assert_eq!(ids(&rules), ["A", "B"]);
+        assert_eq!(rules[0].severity, SeverityLevel::Medium);
+    }
+
+    #[test]
+    fn missing_rules_and_bad_params_are_errors() {
+        let chain = vec![(profile(1, None), vec![entry(1, "Z")])];
+        assert!(resolve_profile(&chain, &catalogue()).is_err());
+
+        let mut mistyped = entry(1, "A");
+        mistyped.params =
+            Json(ParamValues::from([("max_age".to_string(), json!("old"))]));
+        let chain = vec![(profile(1, None), vec![mistyped])];
+        assert!(resolve_profile(&chain, &catalogue()).is_err());
+    }
+}
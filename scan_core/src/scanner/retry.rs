@@ -0,0 +1,168 @@
+use std::fmt;
+use std::future::Future;
+use std::time::Duration;
+
+use anyhow::Result;
+use serde::Deserialize;
+use tokio::time::sleep;
+
+/// The kinds of SSH failure a retry policy can choose to retry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FailureKind {
+    /// The connection could not be established or was dropped.
+    Connect,
+    ConnectTimeout,
+    /// Authentication or host key verification was refused.
+    Auth,
+    /// A command's channel failed.
+    Channel,
+    CommandTimeout,
+}
+
+/// An SSH failure, tagged with its kind so retry policies can tell
+/// transient failures from permanent ones.
+#[derive(Debug)]
+pub struct SshFailure {
+    pub kind: FailureKind,
+    pub message: String,
+}
+
+impl fmt::Display for SshFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for SshFailure {}
+
+/// How often, and after which failures, SSH operations are attempted again.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Attempts in total, the first one included.
+    pub max_attempts: u32,
+    /// Delay before the first retry; doubled for each retry after that.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    pub retry_on: Vec<FailureKind>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(10),
+            retry_on: vec![
+                FailureKind::Connect,
+                FailureKind::ConnectTimeout,
+                FailureKind::Channel,
+            ],
+        }
+    }
+}
+
+impl RetryPolicy {
+    fn is_retryable(self: &Self, e: &anyhow::Error) -> bool {
+        e.chain()
+            .find_map(|cause| cause.downcast_ref::<SshFailure>())
+            .is_some_and(|failure| self.retry_on.contains(&failure.kind))
+    }
+
+    /// Runs `op` until it succeeds, fails with a kind of failure that is not
+    /// retried, or runs out of attempts. Returns the last result along with
+    /// the number of attempts made.
+    pub async fn run<T, F, Fut>(self: &Self, mut op: F) -> (Result<T>, u32)
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let mut backoff = self.initial_backoff;
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            let result = op().await;
+            match &result {
+                Err(e)
+                    if attempts < self.max_attempts && self.is_retryable(e) =>
+                {
+                    sleep(backoff).await;
+                    backoff = (backoff * 2).min(self.max_backoff);
+                }
+                _ => return (result, attempts),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use anyhow::Context;
+
+    use super::*;
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(2),
+            ..RetryPolicy::default()
+        }
+    }
+
+    fn failure(kind: FailureKind) -> anyhow::Error {
+        SshFailure {
+            kind,
+            message: format!("{:?}", kind),
+        }
+        .into()
+    }
+
+    #[tokio::test]
+    async fn retries_until_success() {
+        let calls = Cell::new(0);
+        let (result, attempts) = policy()
+            .run(|| {
+                calls.set(calls.get() + 1);
+                let n = calls.get();
+                async move {
+                    match n {
+                        1 => Err(failure(FailureKind::Connect)),
+                        _ => Ok(n),
+                    }
+                }
+            })
+            .await;
+        assert_eq!(result.unwrap(), 2);
+        assert_eq!(attempts, 2);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_attempts() {
+        let (result, attempts) = policy()
+            .run(|| async {
+                Err::<(), _>(failure(FailureKind::ConnectTimeout))
+                    .context("Failed to connect")
+            })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(attempts, 3);
+    }
+
+    #[tokio::test]
+    async fn does_not_retry_other_failures() {
+        let (result, attempts) = policy()
+            .run(|| async { Err::<(), _>(failure(FailureKind::Auth)) })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+
+        let (result, attempts) = policy()
+            .run(|| async { Err::<(), _>(anyhow::anyhow!("not ssh")) })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+    }
+}
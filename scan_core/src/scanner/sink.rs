@@ -0,0 +1,283 @@
+use std::sync::Mutex;
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::db::Db;
+use crate::db::models::{Facts, OsInfo, RuleOutcome, ScanStatus, Target};
+use crate::scanner::ssh::CacheStats;
+
+/// Where the scanner records scans and their results.
+#[async_trait]
+pub trait ResultSink: Send + Sync {
+    /// Records a new scan of `target` and returns its id.
+    async fn add_scan(
+        self: &Self,
+        target: &Target,
+        status: ScanStatus,
+    ) -> Result<i64>;
+
+    async fn add_result(
+        self: &Self,
+        scan_id: i64,
+        outcome: &RuleOutcome,
+    ) -> Result<()>;
+
+    /// Moves a scan to `status`, stamping when it started or finished.
+    async fn update_scan_status(
+        self: &Self,
+        scan_id: i64,
+        status: ScanStatus,
+    ) -> Result<()>;
+
+    /// Marks a scan as failed, keeping the reason it failed. A scan failing
+    /// before it started is stamped as started too.
+    async fn fail_scan(self: &Self, scan_id: i64, reason: &str) -> Result<()>;
+
+    /// Records how many attempts connecting to the device took.
+    async fn set_connect_attempts(
+        self: &Self,
+        scan_id: i64,
+        attempts: u32,
+    ) -> Result<()>;
+
+    /// Records how often the scan's commands were served from its cache.
+    async fn set_cache_stats(
+        self: &Self,
+        scan_id: i64,
+        stats: CacheStats,
+    ) -> Result<()>;
+
+    /// Records the facts gathered from the device at the start of a scan.
+    async fn set_facts(self: &Self, scan_id: i64, facts: &Facts) -> Result<()>;
+
+    /// Records the OS detected on a device whose recorded OS is missing or
+    /// out of date.
+    async fn update_device_os(
+        self: &Self,
+        scan_id: i64,
+        target: &Target,
+        os: &OsInfo,
+    ) -> Result<()>;
+}
+
+#[async_trait]
+impl ResultSink for Db {
+    async fn add_scan(
+        self: &Self,
+        target: &Target,
+        status: ScanStatus,
+    ) -> Result<i64> {
+        let scan = Db::add_scan(self, target.device_id, status).await?;
+        Ok(scan.id)
+    }
+
+    async fn add_result(
+        self: &Self,
+        scan_id: i64,
+        outcome: &RuleOutcome,
+    ) -> Result<()> {
+        self.add_scan_result(scan_id, outcome).await?;
+        Ok(())
+    }
+
+    async fn update_scan_status(
+        self: &Self,
+        scan_id: i64,
+        status: ScanStatus,
+    ) -> Result<()> {
+        Db::update_scan_status(self, scan_id, status).await?;
+        Ok(())
+    }
+
+    async fn fail_scan(self: &Self, scan_id: i64, reason: &str) -> Result<()> {
+        Db::fail_scan(self, scan_id, reason.to_string()).await?;
+        Ok(())
+    }
+
+    async fn set_connect_attempts(
+        self: &Self,
+        scan_id: i64,
+        attempts: u32,
+    ) -> Result<()> {
+        Db::set_connect_attempts(self, scan_id, attempts as i32).await?;
+        Ok(())
+    }
+
+    async fn set_cache_stats(
+        self: &Self,
+        scan_id: i64,
+        stats: CacheStats,
+    ) -> Result<()> {
+        Db::set_cache_stats(
+            self,
+            scan_id,
+            stats.hits as i32,
+            stats.misses as i32,
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn set_facts(self: &Self, scan_id: i64, facts: &Facts) -> Result<()> {
+        self.set_scan_facts(scan_id, facts).await?;
+        Ok(())
+    }
+
+    async fn update_device_os(
+        self: &Self,
+        _scan_id: i64,
+        target: &Target,
+        os: &OsInfo,
+    ) -> Result<()> {
+        Db::update_device_os(
+            self,
+            target.device_id,
+            os.family.clone(),
+            os.version.clone(),
+        )
+        .await?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ScanReport {
+    pub address: String,
+    pub status: ScanStatus,
+    pub failure_reason: Option<String>,
+    pub os: Option<OsInfo>,
+    pub connect_attempts: u32,
+    pub cache: CacheStats,
+    pub facts: Option<Facts>,
+    pub started_at: Option<DateTime<Utc>>,
+    pub finished_at: Option<DateTime<Utc>>,
+    pub results: Vec<RuleOutcome>,
+}
+
+/// Keeps scan results in memory, for scans that run without a database.
+#[derive(Default)]
+pub struct MemorySink {
+    reports: Mutex<Vec<ScanReport>>,
+}
+
+impl MemorySink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn reports(self: &Self) -> Vec<ScanReport> {
+        self.reports.lock().unwrap().clone()
+    }
+
+    fn with_report<T>(
+        self: &Self,
+        scan_id: i64,
+        f: impl FnOnce(&mut ScanReport) -> T,
+    ) -> Result<T> {
+        let mut reports = self.reports.lock().unwrap();
+        let report = reports
+            .get_mut(scan_id as usize)
+            .context(format!("No scan with id {}", scan_id))?;
+        Ok(f(report))
+    }
+}
+
+#[async_trait]
+impl ResultSink for MemorySink {
+    async fn add_scan(
+        self: &Self,
+        target: &Target,
+        status: ScanStatus,
+    ) -> Result<i64> {
+        let mut reports = self.reports.lock().unwrap();
+        reports.push(ScanReport {
+            address: target.address.clone(),
+            status,
+            failure_reason: None,
+            os: None,
+            connect_attempts: 0,
+            cache: CacheStats::default(),
+            facts: None,
+            started_at: None,
+            finished_at: None,
+            results: Vec::new(),
+        });
+        Ok(reports.len() as i64 - 1)
+    }
+
+    async fn add_result(
+        self: &Self,
+        scan_id: i64,
+        outcome: &RuleOutcome,
+    ) -> Result<()> {
+        self.with_report(scan_id, |report| {
+            report.results.push(outcome.clone());
+        })
+    }
+
+    async fn update_scan_status(
+        self: &Self,
+        scan_id: i64,
+        status: ScanStatus,
+    ) -> Result<()> {
+        self.with_report(scan_id, |report| {
+            match status {
+                ScanStatus::Running => report.started_at = Some(Utc::now()),
+                ScanStatus::Completed | ScanStatus::Failed => {
+                    report.finished_at = Some(Utc::now())
+                }
+                ScanStatus::Pending => (),
+            }
+            report.status = status;
+        })
+    }
+
+    async fn fail_scan(self: &Self, scan_id: i64, reason: &str) -> Result<()> {
+        self.with_report(scan_id, |report| {
+            report.status = ScanStatus::Failed;
+            report.started_at.get_or_insert_with(Utc::now);
+            report.finished_at = Some(Utc::now());
+            report.failure_reason = Some(reason.to_string());
+        })
+    }
+
+    async fn set_connect_attempts(
+        self: &Self,
+        scan_id: i64,
+        attempts: u32,
+    ) -> Result<()> {
+        self.with_report(scan_id, |report| {
+            report.connect_attempts = attempts;
+        })
+    }
+
+    async fn set_cache_stats(
+        self: &Self,
+        scan_id: i64,
+        stats: CacheStats,
+    ) -> Result<()> {
+        self.with_report(scan_id, |report| {
+            report.cache = stats;
+        })
+    }
+
+    async fn set_facts(self: &Self, scan_id: i64, facts: &Facts) -> Result<()> {
+        self.with_report(scan_id, |report| {
+            report.facts = Some(facts.clone());
+        })
+    }
+
+    async fn update_device_os(
+        self: &Self,
+        scan_id: i64,
+        _target: &Target,
+        os: &OsInfo,
+    ) -> Result<()> {
+        self.with_report(scan_id, |report| {
+            report.os = Some(os.clone());
+        })
+    }
+}
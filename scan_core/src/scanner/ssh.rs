@@ -1,52 +1,458 @@
-use std::sync::Arc;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use anyhow::{Context, Result};
-use async_ssh2_tokio::{AuthMethod, Client};
-use mlua::{UserData, UserDataMethods};
+use async_ssh2_tokio::{AuthMethod, Client, ServerCheckMethod};
+use mlua::{LuaSerdeExt, Table, UserData, UserDataMethods};
+use russh::client;
+use russh::keys::{HashAlg, PublicKey};
+use serde::Serialize;
+use tokio::sync::{OnceCell, Semaphore};
+use tokio::time::timeout;
 
+use crate::db::models::{Credential, HostKey};
+use crate::scanner::ScanOptions;
+use crate::scanner::retry::{FailureKind, RetryPolicy, SshFailure};
+
+/// A device's SSH connection, shared by every rule scanning it. Each command
+/// opens a channel of its own, so rules running in parallel multiplex their
+/// commands over the one connection.
+#[derive(Clone)]
 pub struct SSHSession {
-    client: Arc<Client>,
+    connection: Arc<Connection>,
+    command_timeout: Duration,
+    /// Bounds the commands running on the device at the same time.
+    commands: Arc<Semaphore>,
+    retry: RetryPolicy,
+    /// Commands retried since this handle was made by `for_rule`.
+    retries: Arc<AtomicU32>,
+    /// Outputs of the commands run during the current device scan.
+    cache: Option<Arc<CommandCache>>,
+}
+
+/// The full result of a command, as returned to Lua by `conn:exec`.
+#[derive(Debug, Clone, Serialize)]
+pub struct CommandOutput {
+    pub stdout: String,
+    pub stderr: String,
+    pub exit_status: u32,
+    pub duration_ms: u64,
+    /// Whether the output was served from the scan's command cache.
+    pub cached: bool,
+}
+
+/// How often commands were served from the cache during a device scan.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct CacheStats {
+    pub hits: u32,
+    pub misses: u32,
+}
+
+#[derive(Default)]
+struct CommandCache {
+    outputs: Mutex<HashMap<String, Arc<OnceCell<CommandOutput>>>>,
+    hits: AtomicU32,
+    misses: AtomicU32,
 }
 
 impl SSHSession {
     pub async fn new(
-        addr: &str,
+        host: &str,
+        port: u16,
         username: &str,
-        password: &str,
+        credential: &Credential,
+        host_key: &HostKey,
+        options: &ScanOptions,
     ) -> Result<Self> {
-        let authn_method = AuthMethod::with_password(password);
-        let client = Client::connect(
-            addr,
-            username,
-            authn_method,
-            async_ssh2_tokio::ServerCheckMethod::NoCheck,
-        )
-        .await
-        .context(format!("Failed to establish connection to '{}'", addr))?;
+        let endpoint = Endpoint {
+            host: host.to_string(),
+            port,
+            username: username.to_string(),
+            auth: auth_method(credential),
+            host_key: host_key.public_key.clone(),
+            connect_timeout: options.connect_timeout,
+        };
+        let client = endpoint.connect().await?;
 
         Ok(Self {
-            client: Arc::new(client),
+            connection: Arc::new(Connection {
+                endpoint,
+                client: tokio::sync::Mutex::new(Arc::new(client)),
+                reconnects: AtomicU32::new(0),
+            }),
+            command_timeout: options.command_timeout,
+            commands: Arc::new(Semaphore::new(
+                options.concurrency.commands_per_device.max(1),
+            )),
+            retry: options.retry.clone(),
+            retries: Arc::new(AtomicU32::new(0)),
+            cache: None,
         })
     }
 
+    /// Returns a handle on the same connection for a new device scan, with
+    /// an empty command cache, or none at all when `cache` is false.
+    pub fn for_scan(self: &Self, cache: bool) -> Self {
+        Self {
+            cache: cache.then(Default::default),
+            ..self.clone()
+        }
+    }
+
+    pub fn cache_stats(self: &Self) -> CacheStats {
+        self.cache
+            .as_ref()
+            .map_or(CacheStats::default(), |cache| CacheStats {
+                hits: cache.hits.load(Ordering::Relaxed),
+                misses: cache.misses.load(Ordering::Relaxed),
+            })
+    }
+
+    /// Returns a handle on the same connection with its own retry count,
+    /// so each rule can report the retries its commands needed.
+    pub fn for_rule(self: &Self) -> Self {
+        Self {
+            retries: Arc::new(AtomicU32::new(0)),
+            ..self.clone()
+        }
+    }
+
+    pub fn retries(self: &Self) -> u32 {
+        self.retries.load(Ordering::Relaxed)
+    }
+
+    /// Times the connection was re-established after the transport dropped.
+    pub fn reconnects(self: &Self) -> u32 {
+        self.connection.reconnects.load(Ordering::Relaxed)
+    }
+
     pub async fn run_cmd(self: &Self, cmd: &str) -> Result<String> {
-        Ok(self
-            .client
-            .execute(cmd)
-            .await
-            .context(format!("Failed to execute command '{}'", cmd))?
-            .stdout)
+        Ok(self.exec(cmd).await?.stdout)
+    }
+
+    /// Runs a command through the cache unless `cache` is false. Rules pass
+    /// false for commands with side effects, so those run at most once: a
+    /// failed attempt may already have changed the device.
+    async fn exec_with(
+        self: &Self,
+        cmd: &str,
+        cache: bool,
+    ) -> Result<CommandOutput> {
+        match cache {
+            true => self.exec(cmd).await,
+            false => self.exec_once(cmd, false).await,
+        }
+    }
+
+    /// Runs a command, or returns its output from earlier in the scan. Only
+    /// commands without side effects should be run this way.
+    pub async fn exec(self: &Self, cmd: &str) -> Result<CommandOutput> {
+        let Some(cache) = &self.cache else {
+            return self.exec_uncached(cmd).await;
+        };
+        let cell = cache
+            .outputs
+            .lock()
+            .unwrap()
+            .entry(cmd.to_string())
+            .or_default()
+            .clone();
+        // Rules asking for the same command at once wait for a single run.
+        let mut ran = false;
+        let output = cell
+            .get_or_try_init(|| {
+                ran = true;
+                self.exec_uncached(cmd)
+            })
+            .await?;
+        match ran {
+            true => {
+                cache.misses.fetch_add(1, Ordering::Relaxed);
+                Ok(output.clone())
+            }
+            false => {
+                cache.hits.fetch_add(1, Ordering::Relaxed);
+                Ok(CommandOutput {
+                    cached: true,
+                    ..output.clone()
+                })
+            }
+        }
+    }
+
+    /// Runs a command every time it is called, retrying it as the retry
+    /// policy allows. Only commands without side effects should be run
+    /// this way.
+    pub async fn exec_uncached(
+        self: &Self,
+        cmd: &str,
+    ) -> Result<CommandOutput> {
+        let (result, attempts) =
+            self.retry.run(|| self.exec_once(cmd, true)).await;
+        self.retries.fetch_add(attempts - 1, Ordering::Relaxed);
+        result
+    }
+
+    /// Runs a command once. With `rerun`, a command whose transport dropped
+    /// is run once more after reconnecting.
+    async fn exec_once(
+        self: &Self,
+        cmd: &str,
+        rerun: bool,
+    ) -> Result<CommandOutput> {
+        let _permit = self.commands.acquire().await?;
+        let start = Instant::now();
+        let mut client = self.connection.client().await?;
+        let mut reconnected = false;
+        let result = loop {
+            let result = timeout(self.command_timeout, client.execute(cmd))
+                .await
+                .map_err(|_| SshFailure {
+                    kind: FailureKind::CommandTimeout,
+                    message: format!(
+                        "Command '{}' timed out after {}s",
+                        cmd,
+                        self.command_timeout.as_secs()
+                    ),
+                })?;
+            match result {
+                Ok(result) => break result,
+                // The transport dropped rather than the channel failing:
+                // reconnect and run the command once more.
+                Err(_) if rerun && client.is_closed() && !reconnected => {
+                    client = self.connection.client().await?;
+                    reconnected = true;
+                }
+                Err(e) => {
+                    return Err(SshFailure {
+                        kind: FailureKind::Channel,
+                        message: format!(
+                            "Failed to execute command '{}': {}",
+                            cmd, e
+                        ),
+                    }
+                    .into());
+                }
+            }
+        };
+        Ok(CommandOutput {
+            stdout: result.stdout,
+            stderr: result.stderr,
+            exit_status: result.exit_status,
+            duration_ms: start.elapsed().as_millis() as u64,
+            cached: false,
+        })
+    }
+}
+
+/// Everything needed to open a connection to a device again.
+struct Endpoint {
+    host: String,
+    port: u16,
+    username: String,
+    auth: AuthMethod,
+    /// The verified host key the server must keep presenting.
+    host_key: String,
+    connect_timeout: Duration,
+}
+
+impl Endpoint {
+    async fn connect(self: &Self) -> Result<Client> {
+        let client = timeout(
+            self.connect_timeout,
+            Client::connect(
+                (self.host.as_str(), self.port),
+                self.username.as_str(),
+                self.auth.clone(),
+                ServerCheckMethod::PublicKey(self.host_key.clone()),
+            ),
+        )
+        .await
+        .map_err(|_| SshFailure {
+            kind: FailureKind::ConnectTimeout,
+            message: format!(
+                "Connection to '{}:{}' timed out after {}s",
+                self.host,
+                self.port,
+                self.connect_timeout.as_secs()
+            ),
+        })?
+        .map_err(|e| SshFailure {
+            kind: failure_kind(&e),
+            message: format!(
+                "Failed to establish connection to '{}:{}': {}",
+                self.host, self.port, e
+            ),
+        })?;
+        Ok(client)
     }
 }
 
+struct Connection {
+    endpoint: Endpoint,
+    client: tokio::sync::Mutex<Arc<Client>>,
+    reconnects: AtomicU32,
+}
+
+impl Connection {
+    /// Returns the live client, reconnecting first if the transport has
+    /// dropped. Callers arriving during a reconnect wait for it to finish.
+    async fn client(self: &Self) -> Result<Arc<Client>> {
+        let mut client = self.client.lock().await;
+        if client.is_closed() {
+            *client = Arc::new(self.endpoint.connect().await?);
+            self.reconnects.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(client.clone())
+    }
+}
+
+/// Reads a host key as it appears in a known_hosts file.
+pub fn parse_host_key(algorithm: &str, public_key: &str) -> Result<HostKey> {
+    let key = russh::keys::parse_public_key_base64(public_key)
+        .context(format!("Invalid {} host key", algorithm))?;
+    Ok(HostKey {
+        algorithm: algorithm.to_string(),
+        public_key: public_key.to_string(),
+        fingerprint: key.fingerprint(HashAlg::Sha256).to_string(),
+    })
+}
+
+fn host_key_of(key: &PublicKey) -> Result<HostKey> {
+    let openssh = key
+        .to_openssh()
+        .context("Failed to encode server host key")?;
+    let mut parts = openssh.split_whitespace();
+    let algorithm = parts.next().unwrap_or_default();
+    let public_key = parts.next().unwrap_or_default();
+    parse_host_key(algorithm, public_key)
+}
+
+struct HostKeyProbe {
+    key: Arc<Mutex<Option<PublicKey>>>,
+}
+
+impl client::Handler for HostKeyProbe {
+    type Error = russh::Error;
+
+    async fn check_server_key(
+        &mut self,
+        server_public_key: &PublicKey,
+    ) -> Result<bool, Self::Error> {
+        *self.key.lock().unwrap() = Some(server_public_key.clone());
+        // Rejecting the key ends the handshake before authentication.
+        Ok(false)
+    }
+}
+
+/// Connects to a host just long enough to learn the host key it presents.
+pub async fn fetch_host_key(
+    host: &str,
+    port: u16,
+    connect_timeout: Duration,
+) -> Result<HostKey> {
+    let captured = Arc::new(Mutex::new(None));
+    let probe = HostKeyProbe {
+        key: captured.clone(),
+    };
+    let config = Arc::new(client::Config::default());
+    // The probe always rejects the key, so the connection error is expected.
+    let timed_out = timeout(
+        connect_timeout,
+        client::connect(config, (host, port), probe),
+    )
+    .await
+    .is_err();
+
+    let key = captured.lock().unwrap().take().ok_or_else(|| SshFailure {
+        kind: match timed_out {
+            true => FailureKind::ConnectTimeout,
+            false => FailureKind::Connect,
+        },
+        message: format!(
+            "Failed to retrieve host key from '{}:{}'",
+            host, port
+        ),
+    })?;
+    host_key_of(&key)
+}
+
+/// Returns the keys in a known_hosts file that belong to `host` and `port`.
+///
+/// Hashed host names and marker lines (`@revoked`, `@cert-authority`) are
+/// skipped.
+pub fn parse_known_hosts(
+    contents: &str,
+    host: &str,
+    port: u16,
+) -> Vec<HostKey> {
+    // known_hosts only writes the port when it is not 22, as `[host]:port`.
+    let pattern = match port {
+        22 => host.to_string(),
+        _ => format!("[{}]:{}", host, port),
+    };
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with(['#', '@']))
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let hosts = fields.next()?;
+            let algorithm = fields.next()?;
+            let public_key = fields.next()?;
+            hosts
+                .split(',')
+                .any(|h| h == pattern)
+                .then(|| parse_host_key(algorithm, public_key).ok())
+                .flatten()
+        })
+        .collect()
+}
+
+/// Failures to authenticate or to verify the host key are never transient.
+fn failure_kind(e: &async_ssh2_tokio::Error) -> FailureKind {
+    match e {
+        async_ssh2_tokio::Error::PasswordWrong
+        | async_ssh2_tokio::Error::KeyAuthFailed
+        | async_ssh2_tokio::Error::KeyInvalid(_)
+        | async_ssh2_tokio::Error::ServerCheckFailed => FailureKind::Auth,
+        _ => FailureKind::Connect,
+    }
+}
+
+fn auth_method(credential: &Credential) -> AuthMethod {
+    match credential {
+        Credential::Password { password } => {
+            AuthMethod::with_password(password)
+        }
+        Credential::PrivateKey { key, passphrase } => {
+            AuthMethod::with_key(key, passphrase.as_deref())
+        }
+        Credential::Agent => AuthMethod::with_agent(),
+    }
+}
+
+/// Reads the `cache` flag of the options table `conn:exec` and
+/// `conn:run_cmd` take; commands are cached unless it is `false`.
+fn use_cache(opts: Option<Table>) -> mlua::Result<bool> {
+    Ok(match opts {
+        Some(opts) => opts.get::<Option<bool>>("cache")?.unwrap_or(true),
+        None => true,
+    })
+}
+
 impl UserData for SSHSession {
     fn add_methods<M: UserDataMethods<Self>>(methods: &mut M) {
-        // Expose a 'run_command' method to Lua
+        // Expose a 'run_command' method to Lua. Pass `{ cache = false }`
+        // for commands with side effects.
         methods.add_async_method(
             "run_cmd",
-            |_, ssh_session, command: String| async move {
-                match ssh_session.run_cmd(command.as_str()).await {
-                    Ok(result) => Ok(result),
+            |_, ssh_session, (command, opts): (String, Option<Table>)| async move {
+                let cache = use_cache(opts)?;
+                match ssh_session.exec_with(command.as_str(), cache).await {
+                    Ok(output) => Ok(output.stdout),
                     Err(e) => Err(mlua::Error::RuntimeError(format!(
                         "SSH command failed: {}",
                         e
@@ -54,5 +460,67 @@ impl UserData for SSHSession {
                 }
             },
         );
+
+        // Expose 'exec', returning stdout, stderr, exit_status, duration_ms
+        // and cached as a table
+        methods.add_async_method(
+            "exec",
+            |lua, ssh_session, (command, opts): (String, Option<Table>)| async move {
+                let cache = use_cache(opts)?;
+                match ssh_session.exec_with(command.as_str(), cache).await {
+                    Ok(output) => lua.to_value(&output),
+                    Err(e) => Err(mlua::Error::RuntimeError(format!(
+                        "SSH command failed: {}",
+                        e
+                    ))),
+                }
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str =
+        "AAAAC3NzaC1lZDI1NTE5AAAAIOMqqnkVzrm0SdG6UOoqKLsabgH5C9okWi0dh2l9GKJl";
+
+    fn known_hosts() -> String {
+        [
+            "# comment".to_string(),
+            format!("web-1,10.0.0.5 ssh-ed25519 {}", KEY),
+            format!("[10.0.0.6]:2222 ssh-ed25519 {}", KEY),
+            format!("|1|c2FsdA==|aGFzaA== ssh-ed25519 {}", KEY),
+            format!("@revoked 10.0.0.7 ssh-ed25519 {}", KEY),
+            "10.0.0.8 ssh-ed25519 not-base64".to_string(),
+        ]
+        .join("\n")
+    }
+
+    #[test]
+    fn known_hosts_match_any_listed_name() {
+        let keys = parse_known_hosts(&known_hosts(), "10.0.0.5", 22);
+        assert_eq!(keys.len(), 1);
+        assert_eq!(keys[0].algorithm, "ssh-ed25519");
+        assert_eq!(keys[0].public_key, KEY);
+        assert!(keys[0].fingerprint.starts_with("SHA256:"));
+        assert_eq!(parse_known_hosts(&known_hosts(), "web-1", 22).len(), 1);
+    }
+
+    #[test]
+    fn known_hosts_only_match_other_ports_in_brackets() {
+        assert_eq!(
+            parse_known_hosts(&known_hosts(), "10.0.0.6", 2222).len(),
+            1
+        );
+        assert!(parse_known_hosts(&known_hosts(), "10.0.0.6", 22).is_empty());
+        assert!(parse_known_hosts(&known_hosts(), "10.0.0.5", 2222).is_empty());
+    }
+
+    #[test]
+    fn known_hosts_skip_markers_and_invalid_keys() {
+        assert!(parse_known_hosts(&known_hosts(), "10.0.0.7", 22).is_empty());
+        assert!(parse_known_hosts(&known_hosts(), "10.0.0.8", 22).is_empty());
     }
 }
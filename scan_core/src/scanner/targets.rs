@@ -0,0 +1,57 @@
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+
+use crate::db::Db;
+use crate::db::models::Target;
+
+/// The devices a scan should cover.
+#[derive(Debug, Clone)]
+pub enum ScanScope {
+    /// Devices carrying all `tags`, optionally of one OS family. With no
+    /// tags and no OS family this is the whole inventory.
+    Matching {
+        tags: Vec<String>,
+        os_family: Option<String>,
+    },
+    /// Members of the named device group.
+    Group(String),
+    Device(i64),
+    Devices(Vec<i64>),
+}
+
+impl Default for ScanScope {
+    fn default() -> Self {
+        ScanScope::Matching {
+            tags: Vec::new(),
+            os_family: None,
+        }
+    }
+}
+
+/// Resolves a scope to the targets it covers. Disabled devices are never
+/// returned.
+#[async_trait]
+pub trait TargetSource: Send + Sync {
+    async fn targets(self: &Self, scope: &ScanScope) -> Result<Vec<Target>>;
+}
+
+#[async_trait]
+impl TargetSource for Db {
+    async fn targets(self: &Self, scope: &ScanScope) -> Result<Vec<Target>> {
+        let devices = match scope {
+            ScanScope::Matching { tags, os_family } => {
+                self.find_devices(tags.clone(), os_family.clone()).await?
+            }
+            ScanScope::Group(name) => {
+                let group = self
+                    .get_group_by_name(name.clone())
+                    .await?
+                    .context(format!("No device group named '{}'", name))?;
+                self.get_group_devices(group.id).await?
+            }
+            ScanScope::Device(id) => self.get_devices(vec![*id]).await?,
+            ScanScope::Devices(ids) => self.get_devices(ids.clone()).await?,
+        };
+        self.targets_for(devices).await
+    }
+}
@@ -10,13 +10,11 @@ METADATA = {
 	name = "Is NetBSD",
 	description = "Checks if the target is netBSD",
 	severity = "Info",
+	applies_to = { os_family = { "netbsd" } },
 }
 
 function run_check()
-	local re = regex.compile("NetBSD")
-	local cmd_out = conn:run_cmd("uname -a")
-
-	if re:is_match(cmd_out) then
+	if facts.os and facts.os.family == "netbsd" then
 		return { status = "Pass", details = "SSH configuration is secure." }
 	else
 		return { status = "Fail", details = "Not NetBSD" }
@@ -10,17 +10,21 @@ local function is_bsd(conn)
 end
 
 local function has_ufw(conn)
-	local cmd_out = conn:run_cmd('dpkg-query -s ufw &>/dev/null && echo "good"')
-	if string.find(cmd_out, "good") then
+	local result = conn:exec("dpkg-query -s ufw")
+	if result.exit_status == 0 then
 		return true, "ufw installed"
 	else
 		return false, "ufw not installed"
 	end
 end
 
+local function uses_systemd(conn)
+	return facts.init_system == "systemd"
+end
+
 local function one_fw(conn)
-	local cmd_out = conn:run_cmd([[
-	   active_firewall=() firewalls=("ufw" "nftables" "iptables")
+	local script = [[
+	   active_firewall=() firewalls=(FIREWALLS)
 	   # Determine which firewall is in use
 	   for firewall in "${firewalls[@]}"; do
 	   case $firewall in
@@ -45,7 +49,10 @@ local function one_fw(conn)
 	   printf '%s\n' "" " Audit Results:" " ** FAIL **" " - Multiple firewalls
 	   are in use: ${active_firewall[*]}"
 	   fi
-	 ]])
+	 ]]
+	local cmd_out = conn:run_cmd(
+		(script:gsub("FIREWALLS", table.concat(params.firewalls, " ")))
+	)
 
 	if string.find(cmd_out, "PASS") then
 		return true, "One firewall in use"
@@ -59,10 +66,11 @@ end
 -------------------------------------------------------------------
 
 register_check({
-	id = "BSD-123",
+	id = "BSD-124",
 	name = "Is NetBSD",
 	description = "Checks if the target is NetBSD",
 	severity = "Info",
+	applies_to = { os_family = { "netbsd" } },
 	run = is_bsd,
 })
 
@@ -71,13 +79,23 @@ register_check({
 	name = "ufw is Installed",
 	description = "Checks if the ufw firewall is installed",
 	severity = "Medium",
+	applies_to = { os_family = { "ubuntu", "debian" } },
 	run = has_ufw,
 })
 
 register_check({
-	id = "UBU-101",
+	id = "UBU-102",
 	name = "One FW Active",
 	description = "Check to ensure that only one firewall is active",
 	severity = "Medium",
+	applies_to = { os_family = { "ubuntu" }, min_version = "20.04" },
+	applies = uses_systemd,
+	params = {
+		firewalls = {
+			type = "list",
+			default = { "ufw", "nftables", "iptables" },
+			description = "Firewalls to look for",
+		},
+	},
 	run = one_fw,
 })
@@ -10,11 +10,12 @@ METADATA = {
 	name = "ufw Installed",
 	description = "Checks if the ufw firewall is installed",
 	severity = "Low",
+	applies_to = { os_family = { "ubuntu", "debian" } },
 }
 
 function run_check()
-	local cmd_out = conn:run_cmd('dpkg-query -s ufw &>/dev/null && echo "good"')
-	if string.find(cmd_out, "good") then
+	local result = conn:exec("dpkg-query -s ufw")
+	if result.exit_status == 0 then
 		return { status = "Pass", details = "UFW is installed." }
 	else
 		return { status = "Fail", details = "UFW is not installed." }